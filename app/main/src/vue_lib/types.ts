@@ -1,6 +1,7 @@
 import { State } from '@martichou/core_lib/bindings/State';
 import { DeviceType } from '@martichou/core_lib/bindings/DeviceType';
 import { Visibility } from '@martichou/core_lib/bindings/Visibility';
+import { CancelOrigin } from '@martichou/core_lib/bindings/CancelOrigin';
 
 export interface ToDelete {
 	id: string,
@@ -22,6 +23,7 @@ export interface DisplayedItem {
 	destination?: string,
 	total_bytes?: number,
 	ack_bytes?: number,
+	cancelled_by?: CancelOrigin,
 }
 
 export const visibilityToNumber: { [key in Visibility]: number } = {
@@ -42,7 +44,7 @@ export const startminimizedKey = "startminimized";
 export const visibilityKey = "visibility";
 export const downloadPathKey = "download_path";
 export const stateToDisplay: Array<Partial<State>> = ["ReceivedPairedKeyResult", "WaitingForUserConsent", "ReceivingFiles", "Disconnected",
-	"Finished", "SentIntroduction", "SendingFiles", "Cancelled", "Rejected"]
+	"Finished", "SentIntroduction", "SendingFiles", "Cancelled", "Rejected", "NotEnoughSpace", "TimedOut", "SecurityError"]
 
 export interface Toast {
 	id: number;
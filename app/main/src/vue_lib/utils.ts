@@ -38,6 +38,7 @@ function _displayedItems(vm: TauriVM): Array<DisplayedItem> {
 			text_type: el.meta?.text_type ?? undefined,
 			ack_bytes: (el.meta?.ack_bytes as number | undefined) ?? undefined,
 			total_bytes: (el.meta?.total_bytes as number | undefined) ?? undefined,
+			cancelled_by: el.meta?.cancelled_by ?? undefined,
 		};
 
 		if (idx !== -1) {
@@ -154,6 +155,7 @@ async function sendCmd(vm: TauriVM, id: string, action: ChannelAction) {
 		meta: null,
 		state: null,
 		rtype: null,
+		group_id: null,
 	};
 	console.log("js2rs:", cm);
 
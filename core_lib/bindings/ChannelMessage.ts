@@ -5,4 +5,4 @@ import type { State } from "./State";
 import type { TransferMetadata } from "./TransferMetadata";
 import type { TransferType } from "./TransferType";
 
-export type ChannelMessage = { id: string, direction: ChannelDirection, action: ChannelAction | null, rtype: TransferType | null, state: State | null, meta: TransferMetadata | null, };
+export type ChannelMessage = { id: string, direction: ChannelDirection, action: ChannelAction | null, rtype: TransferType | null, state: State | null, meta: TransferMetadata | null, group_id: string | null, };
@@ -1,3 +1,4 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { WifiSecurityType } from "./WifiSecurityType";
 
-export type OutboundPayload = { "Files": Array<string> };
+export type OutboundPayload = { "Files": Array<string> } | { "Text": string } | { "Url": string } | { "WifiCredentials": { ssid: string, password: string, security_type: WifiSecurityType, hidden: boolean, } };
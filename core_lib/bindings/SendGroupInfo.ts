@@ -0,0 +1,9 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { OutboundPayload } from "./OutboundPayload";
+import type { SendTarget } from "./SendTarget";
+
+/**
+ * Same payload, sent to every target in parallel. Each transfer is
+ * reported under its target's id, with group_id set.
+ */
+export type SendGroupInfo = { group_id: string, targets: Array<SendTarget>, ob: OutboundPayload, };
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { TextPayloadType } from "./TextPayloadType";
+
+export type TransferItem = { payload_id: bigint, name: string, size: bigint, text_type: TextPayloadType | null, text_payload: string | null, };
@@ -1,3 +1,4 @@
+export * from "./CancelOrigin"
 export * from "./ChannelAction"
 export * from "./ChannelDirection"
 export * from "./ChannelMessage"
@@ -5,9 +6,13 @@ export * from "./DeviceType"
 export * from "./EndpointInfo"
 export * from "./OutboundPayload"
 export * from "./RemoteDeviceInfo"
+export * from "./SendGroupInfo"
 export * from "./SendInfo"
+export * from "./SendTarget"
 export * from "./State"
 export * from "./TextPayloadType"
+export * from "./TransferItem"
 export * from "./TransferMetadata"
 export * from "./TransferType"
-export * from "./Visibility"
\ No newline at end of file
+export * from "./Visibility"
+export * from "./WifiSecurityType"
\ No newline at end of file
@@ -9,6 +9,7 @@ fn main() {
         &[
             "src/proto_src/device_to_device_messages.proto",
             "src/proto_src/offline_wire_formats.proto",
+            "src/proto_src/rquickshare_ext.proto",
             "src/proto_src/securegcm.proto",
             "src/proto_src/securemessage.proto",
             "src/proto_src/ukey.proto",
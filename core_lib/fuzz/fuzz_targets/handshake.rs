@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rqs_lib::fuzzing::{
+    parse_connection_request, parse_ukey2_client_finish, parse_ukey2_client_init,
+};
+use rqs_lib::securegcm::ukey2_client_init::CipherCommitment;
+use rqs_lib::securegcm::Ukey2HandshakeCipher;
+use sha2::{Digest, Sha512};
+
+// The frames received before anything is encrypted
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_connection_request(data);
+    let _ = parse_ukey2_client_init(data);
+
+    // Committing to the data itself, to get past the commitment check
+    let commitment = CipherCommitment {
+        handshake_cipher: Some(Ukey2HandshakeCipher::Curve25519Sha512.into()),
+        commitment: Some(Sha512::digest(data).to_vec()),
+    };
+    let _ = parse_ukey2_client_finish(data, &commitment);
+});
@@ -0,0 +1,102 @@
+#![no_main]
+
+use std::sync::{Arc, RwLock};
+
+use libfuzzer_sys::fuzz_target;
+use rqs_lib::channel::{ChannelAction, ChannelDirection, ChannelMessage};
+use rqs_lib::{InboundRequest, ResumeStore, SecureChannel, SecureChannelRole, State};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+fn channel(role: SecureChannelRole) -> SecureChannel {
+    let derived_secret: Vec<u8> = (0..32).collect();
+    SecureChannel::new(role, &derived_secret, b"client init", b"server init").unwrap()
+}
+
+// Frames as the remote device would send them, each prefixed by
+// its length on two bytes in the fuzzed data.
+fn split_frames(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut frames = Vec::new();
+    while data.len() >= 2 {
+        let len = (u16::from_be_bytes([data[0], data[1]]) as usize).min(data.len() - 2);
+        frames.push(&data[2..2 + len]);
+        data = &data[2 + len..];
+    }
+
+    frames
+}
+
+// The whole receiving side, the first byte picking whether the frames are
+// those of the handshake or the ones encrypted once it's done.
+fuzz_target!(|data: &[u8]| {
+    let Some((&mode, data)) = data.split_first() else {
+        return;
+    };
+    let encrypted = mode & 1 == 1;
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    let download_dir = std::env::temp_dir().join(format!("rqs_fuzz_{}", std::process::id()));
+    rt.block_on(async {
+        let (socket, remote) = tokio::io::duplex(64 * 1024);
+        let (sender, _) = broadcast::channel(50);
+        let download_path = Arc::new(RwLock::new(Some(download_dir.clone())));
+        let mut ir = InboundRequest::new(
+            socket,
+            String::from("fuzz"),
+            sender.clone(),
+            None,
+            download_path,
+            Arc::new(RwLock::new(None)),
+            Arc::new(ResumeStore::default()),
+        );
+
+        let mut client = None;
+        if encrypted {
+            ir = ir.with_secure_channel(channel(SecureChannelRole::Server));
+            client = Some(channel(SecureChannelRole::Client));
+        }
+
+        let mut frames = Vec::new();
+        for frame in split_frames(data) {
+            let frame = match client.as_mut() {
+                Some(c) => c.encrypt(frame).unwrap(),
+                None => frame.to_vec(),
+            };
+            frames.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            frames.extend_from_slice(&frame);
+        }
+
+        // Whatever is answered is ignored, the remote then stops sending
+        let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut remote_read, &mut tokio::io::sink()).await;
+        });
+        tokio::spawn(async move {
+            let _ = remote_write.write_all(&frames).await;
+            let _ = remote_write.shutdown().await;
+        });
+
+        let mut accepted = false;
+        loop {
+            if !accepted && ir.state.state == State::WaitingForUserConsent {
+                accepted = true;
+                let _ = sender.send(ChannelMessage {
+                    id: String::from("fuzz"),
+                    direction: ChannelDirection::FrontToLib,
+                    action: Some(ChannelAction::AcceptTransfer),
+                    ..Default::default()
+                });
+            }
+
+            if ir.handle().await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let _ = std::fs::remove_dir_all(&download_dir);
+});
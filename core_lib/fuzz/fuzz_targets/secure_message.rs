@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rqs_lib::{SecureChannel, SecureChannelRole};
+
+fn channel(role: SecureChannelRole) -> SecureChannel {
+    let derived_secret: Vec<u8> = (0..32).collect();
+    SecureChannel::new(role, &derived_secret, b"client init", b"server init").unwrap()
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever comes from the network, signed or not
+    let mut server = channel(SecureChannelRole::Server);
+    let _ = server.decrypt(data);
+
+    // And what a well-behaved client would send
+    let mut client = channel(SecureChannelRole::Client);
+    let mut server = channel(SecureChannelRole::Server);
+    let smsg = client.encrypt(data).unwrap();
+    assert_eq!(server.decrypt(&smsg).unwrap(), data);
+});
@@ -18,6 +18,9 @@ pub enum ChannelAction {
     AcceptTransfer,
     RejectTransfer,
     CancelTransfer,
+    // Single payload of a transfer, by payload_id
+    SkipPayload(i64),
+    CancelPayload(i64),
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TS)]
@@ -40,4 +43,6 @@ pub struct ChannelMessage {
     pub rtype: Option<TransferType>,
     pub state: Option<State>,
     pub meta: Option<TransferMetadata>,
+    // Shared by the transfers of a same fan-out send
+    pub group_id: Option<String>,
 }
@@ -0,0 +1,237 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use prost::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::{
+    Medium, WifiLanSocket,
+};
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::{
+    ClientIntroduction, ClientIntroductionAck, EventType, UpgradePathInfo,
+};
+use crate::location_nearby_connections::{
+    offline_frame, v1_frame, BandwidthUpgradeNegotiationFrame, OfflineFrame, V1Frame,
+};
+
+// The client introduction is small, anything bigger is garbage
+const MAX_INTRODUCTION_LENGTH: usize = 4096;
+// How long each step of the upgrade on the new socket may take
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) fn bwu_frame(
+    event_type: EventType,
+    upgrade_path_info: Option<UpgradePathInfo>,
+) -> OfflineFrame {
+    negotiation_frame(BandwidthUpgradeNegotiationFrame {
+        event_type: Some(event_type.into()),
+        upgrade_path_info,
+        ..Default::default()
+    })
+}
+
+fn negotiation_frame(bwu: BandwidthUpgradeNegotiationFrame) -> OfflineFrame {
+    OfflineFrame {
+        version: Some(offline_frame::Version::V1.into()),
+        v1: Some(V1Frame {
+            r#type: Some(v1_frame::FrameType::BandwidthUpgradeNegotiation.into()),
+            bandwidth_upgrade_negotiation: Some(bwu),
+            ..Default::default()
+        }),
+    }
+}
+
+pub(crate) fn wifi_lan_path(addr: &SocketAddr) -> UpgradePathInfo {
+    let ip_address = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    UpgradePathInfo {
+        medium: Some(Medium::WifiLan.into()),
+        wifi_lan_socket: Some(WifiLanSocket {
+            ip_address: Some(ip_address),
+            wifi_port: Some(addr.port() as i32),
+        }),
+        supports_client_introduction_ack: Some(true),
+        ..Default::default()
+    }
+}
+
+/// Address to connect to for a WIFI_LAN upgrade path, if it's a valid one.
+pub(crate) fn wifi_lan_address(info: &UpgradePathInfo) -> Option<SocketAddr> {
+    if info.medium() != Medium::WifiLan {
+        return None;
+    }
+
+    let socket = info.wifi_lan_socket.as_ref()?;
+    let port = u16::try_from(socket.wifi_port()).ok().filter(|p| *p != 0)?;
+    let ip = match socket.ip_address() {
+        b if b.len() == 4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(b).ok()?)),
+        b if b.len() == 16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(b).ok()?)),
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Listen for the upgraded connection on the interface the remote device
+/// already reached us on.
+pub(crate) async fn listen_wifi_lan(local: SocketAddr) -> Result<TcpListener, anyhow::Error> {
+    Ok(TcpListener::bind(SocketAddr::new(local.ip(), 0)).await?)
+}
+
+/// Never resolves when there is no listener, to be used in a select.
+pub(crate) async fn accept_upgrade(
+    listener: &Option<TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(l) => l.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Connect to the upgrade path and introduce ourself, as the responder.
+///
+/// The introduction (and its ack) are sent before the new socket
+/// is encrypted, just like Nearby Connections does.
+pub(crate) async fn connect_wifi_lan(
+    addr: SocketAddr,
+    endpoint_id: &str,
+    wait_ack: bool,
+) -> Result<TcpStream, anyhow::Error> {
+    tokio::time::timeout(UPGRADE_TIMEOUT, async {
+        let mut socket = TcpStream::connect(addr).await?;
+
+        let intro = negotiation_frame(BandwidthUpgradeNegotiationFrame {
+            event_type: Some(EventType::ClientIntroduction.into()),
+            client_introduction: Some(ClientIntroduction {
+                endpoint_id: Some(endpoint_id.to_owned()),
+                supports_disabling_encryption: Some(false),
+            }),
+            ..Default::default()
+        });
+        write_plain_frame(&mut socket, &intro).await?;
+
+        if wait_ack {
+            let ack = read_plain_frame(&mut socket).await?;
+            if event_type(&ack) != Some(EventType::ClientIntroductionAck) {
+                return Err(anyhow!("Expected a client introduction ack"));
+            }
+        }
+
+        Ok(socket)
+    })
+    .await
+    .map_err(|_| anyhow!("Timed out upgrading to {addr}"))?
+}
+
+/// Check the introduction of the responder on the freshly accepted socket.
+pub(crate) async fn accept_introduction(
+    socket: &mut TcpStream,
+    endpoint_id: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    tokio::time::timeout(UPGRADE_TIMEOUT, async {
+        let intro = read_plain_frame(socket).await?;
+        if event_type(&intro) != Some(EventType::ClientIntroduction) {
+            return Err(anyhow!("Expected a client introduction"));
+        }
+
+        let remote_id = intro
+            .v1
+            .as_ref()
+            .and_then(|v1| v1.bandwidth_upgrade_negotiation.as_ref())
+            .and_then(|bwu| bwu.client_introduction.as_ref())
+            .map(|ci| ci.endpoint_id());
+        if endpoint_id.is_some() && remote_id != endpoint_id {
+            return Err(anyhow!("Client introduction from an unknown endpoint"));
+        }
+
+        let ack = negotiation_frame(BandwidthUpgradeNegotiationFrame {
+            event_type: Some(EventType::ClientIntroductionAck.into()),
+            client_introduction_ack: Some(ClientIntroductionAck {}),
+            ..Default::default()
+        });
+        write_plain_frame(socket, &ack).await
+    })
+    .await
+    .map_err(|_| anyhow!("Timed out waiting for the client introduction"))?
+}
+
+fn event_type(frame: &OfflineFrame) -> Option<EventType> {
+    let v1 = frame.v1.as_ref()?;
+    if v1.r#type() != v1_frame::FrameType::BandwidthUpgradeNegotiation {
+        return None;
+    }
+
+    Some(v1.bandwidth_upgrade_negotiation.as_ref()?.event_type())
+}
+
+async fn write_plain_frame(
+    socket: &mut TcpStream,
+    frame: &OfflineFrame,
+) -> Result<(), anyhow::Error> {
+    let data = frame.encode_to_vec();
+    socket.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    socket.write_all(&data).await?;
+    socket.flush().await?;
+
+    Ok(())
+}
+
+async fn read_plain_frame(socket: &mut TcpStream) -> Result<OfflineFrame, anyhow::Error> {
+    let mut length_buf = [0u8; 4];
+    socket.read_exact(&mut length_buf).await?;
+    let length = u32::from_be_bytes(length_buf) as usize;
+    if length > MAX_INTRODUCTION_LENGTH {
+        return Err(anyhow!("Introduction frame too big"));
+    }
+
+    let mut data = vec![0u8; length];
+    socket.read_exact(&mut data).await?;
+
+    Ok(OfflineFrame::decode(&*data)?)
+}
+
+/// Sockets involved in a bandwidth upgrade, until the prior one is drained.
+#[derive(Debug)]
+pub(crate) struct BandwidthUpgrade<S> {
+    // Waiting for the responder to connect, as the initiator
+    pub listener: Option<TcpListener>,
+    // Introduced, but not used until the prior socket is safe to close
+    pub socket: Option<S>,
+    // Still read from until the remote says it's safe to close
+    pub prior_socket: Option<S>,
+}
+
+// Not derived, S doesn't need to be Default
+impl<S> Default for BandwidthUpgrade<S> {
+    fn default() -> Self {
+        Self {
+            listener: None,
+            socket: None,
+            prior_socket: None,
+        }
+    }
+}
+
+impl<S> BandwidthUpgrade<S> {
+    /// Write to the upgraded socket from now on, keeping the current one
+    /// around to read what the remote sent before its last write.
+    pub fn switch_writes(&mut self, current: &mut S) -> bool {
+        match self.socket.take() {
+            Some(socket) => {
+                self.prior_socket = Some(std::mem::replace(current, socket));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close the prior socket, everything is now read from the upgraded one.
+    pub fn switch_reads(&mut self) -> bool {
+        self.prior_socket.take().is_some()
+    }
+}
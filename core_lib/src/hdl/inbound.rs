@@ -1,82 +1,148 @@
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
 use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use anyhow::anyhow;
-use bytes::Bytes;
-use hmac::{Hmac, Mac};
-use libaes::{Cipher, AES_256_KEY_LEN};
-use p256::ecdh::diffie_hellman;
-use p256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
-use p256::{EncodedPoint, PublicKey};
 use prost::Message;
 use rand::Rng;
-use sha2::{Digest, Sha256, Sha512};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::broadcast::{Receiver, Sender};
-
-use super::{InnerState, State};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use super::{
+    accept_introduction, accept_upgrade, append_bytes_chunk, bwu_frame, get_part_path,
+    listen_wifi_lan, parse_connection_request, parse_ukey2_client_finish, parse_ukey2_client_init,
+    resume_key, wifi_lan_path, BandwidthUpgrade, InboundStream, InnerState, KeepAlive,
+    ProtocolError, ResumeStore, SecureChannel, SecureChannelError, SecureChannelRole, State,
+    Transport, STREAM_BUFFER_SIZE, STREAM_STALL_TIMEOUT,
+};
 use crate::channel::{ChannelAction, ChannelDirection, ChannelMessage};
-use crate::hdl::info::{InternalFileInfo, TransferMetadata};
+use crate::hdl::info::{CancelOrigin, InternalFileInfo, TransferItem, TransferMetadata};
 use crate::hdl::{TextPayloadInfo, TextPayloadType};
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::EventType;
+use crate::location_nearby_connections::connection_request_frame::Medium;
 use crate::location_nearby_connections::payload_transfer_frame::{
-    payload_header, PacketType, PayloadChunk, PayloadHeader,
+    control_message, payload_header, ControlMessage, PacketType, PayloadChunk, PayloadHeader,
+};
+use crate::location_nearby_connections::{
+    BandwidthUpgradeNegotiationFrame, DisconnectionFrame, KeepAliveFrame, OfflineFrame,
+    PayloadTransferFrame,
 };
-use crate::location_nearby_connections::{KeepAliveFrame, OfflineFrame, PayloadTransferFrame};
 use crate::securegcm::ukey2_alert::AlertType;
 use crate::securegcm::{
-    ukey2_message, DeviceToDeviceMessage, GcmMetadata, Type, Ukey2Alert, Ukey2ClientFinished,
-    Ukey2ClientInit, Ukey2HandshakeCipher, Ukey2Message, Ukey2ServerInit,
+    ukey2_message, Ukey2Alert, Ukey2HandshakeCipher, Ukey2Message, Ukey2ServerInit,
 };
-use crate::securemessage::{
-    EcP256PublicKey, EncScheme, GenericPublicKey, Header, HeaderAndBody, PublicKeyType,
-    SecureMessage, SigScheme,
-};
-use crate::sharing_nearby::{paired_key_result_frame, text_metadata};
+use crate::sharing_nearby::{paired_key_result_frame, text_metadata, WifiCredentials};
 use crate::utils::{
-    encode_point, gen_ecdsa_keypair, gen_random, get_download_dir, hkdf_extract_expand,
-    stream_read_exact, to_four_digit_string, DeviceType, RemoteDeviceInfo,
+    available_space, encode_p256_public_key, gen_ecdsa_keypair, gen_random, gen_x25519_keypair,
+    get_consent_timeout, get_download_dir, sanitize_filename, sanitize_relative_path,
+    stream_read_exact, ukey2_derived_secret,
 };
 use crate::{location_nearby_connections, sharing_nearby};
 
-type HmacSha256 = Hmac<Sha256>;
-
 const SANE_FRAME_LENGTH: i32 = 5 * 1024 * 1024;
 const SANITY_DURATION: Duration = Duration::from_micros(10);
+// How long to wait for the sender to disconnect once everything is received
+const SAFE_TO_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Received file bytes are acked every that many bytes, and once complete
+const ACK_INTERVAL_BYTES: i64 = 4 * 1024 * 1024;
 
 #[derive(Debug)]
-pub struct InboundRequest {
-    socket: TcpStream,
+pub struct InboundRequest<S = TcpStream> {
+    socket: S,
     pub state: InnerState,
     sender: Sender<ChannelMessage>,
     receiver: Receiver<ChannelMessage>,
+    stream_sender: Option<mpsc::Sender<InboundStream>>,
+    download_path: Arc<RwLock<Option<PathBuf>>>,
+    consent_timeout: Arc<RwLock<Option<Duration>>>,
+    // Temporary files, and the partial ones kept to be resumed
+    resume: Arc<ResumeStore>,
+    // Cancelled payloads, chunks still in flight for them are ignored
+    dropped_payloads: HashMap<i64, payload_header::PayloadType>,
+    // Set while waiting for the user to accept the transfer
+    consent_deadline: Option<Instant>,
+    keep_alive: KeepAlive,
+    bwu: BandwidthUpgrade<S>,
+    // Used to check the client introduction of the bandwidth upgrade
+    remote_endpoint_id: Option<String>,
+    // The sender supports upgrading to another socket on the same network
+    remote_supports_wifi_lan: bool,
+    // Set once finished, while waiting for the sender to disconnect
+    disconnect_deadline: Option<Instant>,
+    // The frame length is read in a cancel-safe way, as the timers above
+    // may fire in the middle of it.
+    length_buf: [u8; 4],
+    length_read: usize,
 }
 
-impl InboundRequest {
-    pub fn new(socket: TcpStream, id: String, sender: Sender<ChannelMessage>) -> Self {
+impl<S: Transport> InboundRequest<S> {
+    pub fn new(
+        socket: S,
+        id: String,
+        sender: Sender<ChannelMessage>,
+        stream_sender: Option<mpsc::Sender<InboundStream>>,
+        download_path: Arc<RwLock<Option<PathBuf>>>,
+        consent_timeout: Arc<RwLock<Option<Duration>>>,
+        resume: Arc<ResumeStore>,
+    ) -> Self {
         let receiver = sender.subscribe();
 
         Self {
             socket,
             state: InnerState {
                 id,
-                server_seq: 0,
-                client_seq: 0,
                 state: State::Initial,
                 encryption_done: true,
                 ..Default::default()
             },
             sender,
             receiver,
+            stream_sender,
+            download_path,
+            consent_timeout,
+            resume,
+            dropped_payloads: HashMap::new(),
+            consent_deadline: None,
+            keep_alive: KeepAlive::default(),
+            bwu: BandwidthUpgrade::default(),
+            remote_endpoint_id: None,
+            remote_supports_wifi_lan: false,
+            disconnect_deadline: None,
+            length_buf: [0u8; 4],
+            length_read: 0,
         }
     }
 
-    pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
-        // Buffer for the 4-byte length
-        let mut length_buf = [0u8; 4];
+    /// Go on as if the UKEY2 handshake was done and led to `channel`, for
+    /// the fuzz targets to reach what is only received encrypted.
+    #[cfg(feature = "fuzzing")]
+    pub fn with_secure_channel(mut self, channel: SecureChannel) -> Self {
+        self.state.state = State::SentConnectionResponse;
+        self.state.secure_channel = Some(channel);
+        self
+    }
+
+    fn download_dir(&self) -> PathBuf {
+        get_download_dir(&self.download_path)
+    }
 
+    /// Remove the files being received, without keeping them for resume.
+    pub fn discard_files(&mut self) {
+        for (_, mfi) in self.state.transferred_files.drain() {
+            self.resume.remove_part(&mfi);
+        }
+    }
+
+    pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
         tokio::select! {
+            biased;
+
             i = self.receiver.recv() => {
                 match i {
                     Ok(channel_msg) => {
@@ -107,15 +173,15 @@ impl InboundRequest {
                                 return Err(anyhow!(crate::errors::AppError::NotAnError));
                             },
                             Some(ChannelAction::CancelTransfer) => {
-                                self.update_state(
-                                    |e| {
-                                        e.state = State::Cancelled;
-                                    },
-                                    true,
-                                ).await;
-                                self.disconnection().await?;
+                                self.cancel(CancelOrigin::Local).await?;
                                 return Err(anyhow!(crate::errors::AppError::NotAnError));
                             },
+                            Some(ChannelAction::SkipPayload(payload_id)) => {
+                                self.skip_payload(payload_id).await?;
+                            },
+                            Some(ChannelAction::CancelPayload(_)) => {
+                                trace!("inbound: CancelPayload is for outbound transfers")
+                            },
                             None => {
                                 trace!("inbound: nothing to do")
                             },
@@ -126,10 +192,74 @@ impl InboundRequest {
                     }
                 }
             },
-            h = stream_read_exact(&mut self.socket, &mut length_buf) => {
-                h?;
+            _ = tokio::time::sleep_until(self.consent_deadline.unwrap_or_else(Instant::now)),
+                if self.consent_deadline.is_some()
+                    && self.state.state == State::WaitingForUserConsent => {
+                info!("Consent timed out");
+                self.update_state(
+                    |e| {
+                        e.state = State::TimedOut;
+                    },
+                    true,
+                ).await;
+
+                self.reject_transfer(Some(
+                    sharing_nearby::connection_response_frame::Status::TimedOut
+                )).await?;
+                return Err(anyhow!(crate::errors::AppError::NotAnError));
+            },
+            _ = tokio::time::sleep_until(self.disconnect_deadline.unwrap_or_else(Instant::now)),
+                if self.disconnect_deadline.is_some() => {
+                debug!("The sender didn't disconnect, closing");
+                let _ = self.disconnection().await;
+                return Err(anyhow!(crate::errors::AppError::NotAnError));
+            },
+            _ = tokio::time::sleep_until(self.keep_alive.deadline()) => {
+                if self.state.state == State::Finished {
+                    return Err(anyhow!(crate::errors::AppError::NotAnError));
+                }
+
+                warn!("Nothing received from the remote device for {:?}, disconnecting", self.keep_alive.timeout);
+                // The frontend only knows about the transfer once it has metadata
+                let inform = self.state.transfer_metadata.is_some();
+                self.update_state(
+                    |e| {
+                        e.state = State::Disconnected;
+                    },
+                    inform,
+                ).await;
+                return Err(anyhow!(crate::errors::AppError::NotAnError));
+            },
+            r = accept_upgrade(&self.bwu.listener) => {
+                match r {
+                    Ok((socket, addr)) => {
+                        debug!("Bandwidth upgrade connection from {addr}");
+                        self.process_upgrade_connection(socket).await?;
+                    }
+                    Err(e) => {
+                        warn!("Couldn't accept the bandwidth upgrade: {e}");
+                        self.bwu.listener = None;
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(self.keep_alive.next_send()), if self.state.secure_channel.is_some() => {
+                trace!("Sending keepalive");
+                self.keep_alive.sent();
+                self.send_keepalive(false).await?;
+            },
+            n = self.bwu.prior_socket.as_mut().unwrap_or(&mut self.socket)
+                .read(&mut self.length_buf[self.length_read..]) => {
+                let n = n?;
+                if n == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
 
-                self._handle(length_buf).await?
+                self.keep_alive.received();
+                self.length_read += n;
+                if self.length_read == self.length_buf.len() {
+                    self.length_read = 0;
+                    self._handle(self.length_buf).await?
+                }
             }
         }
 
@@ -146,16 +276,23 @@ impl InboundRequest {
 
         // Allocate buffer for the actual message and read it
         let mut frame_data = vec![0u8; msg_length];
-        stream_read_exact(&mut self.socket, &mut frame_data).await?;
+        // Until the upgrade is done, frames keep coming from the prior socket
+        let reader = self.bwu.prior_socket.as_mut().unwrap_or(&mut self.socket);
+        stream_read_exact(reader, &mut frame_data).await?;
 
         let current_state = &self.state;
         // Now determine what will be the request type based on current state
         match current_state.state {
             State::Initial => {
                 debug!("Handling State::Initial frame");
-                let frame = location_nearby_connections::OfflineFrame::decode(&*frame_data)?;
-                let rdi = self.process_connection_request(&frame)?;
+                let (cr, rdi) = parse_connection_request(&frame_data)?;
                 info!("RemoteDeviceInfo: {:?}", &rdi);
+                self.keep_alive.configure(
+                    cr.keep_alive_interval_millis(),
+                    cr.keep_alive_timeout_millis(),
+                );
+                self.remote_endpoint_id = cr.endpoint_id.clone();
+                self.remote_supports_wifi_lan = cr.mediums().any(|m| m == Medium::WifiLan);
 
                 // Advance current state
                 self.update_state(
@@ -169,8 +306,7 @@ impl InboundRequest {
             }
             State::ReceivedConnectionRequest => {
                 debug!("Handling State::ReceivedConnectionRequest frame");
-                let msg = Ukey2Message::decode(&*frame_data)?;
-                self.process_ukey2_client_init(&msg).await?;
+                self.process_ukey2_client_init(&frame_data).await?;
 
                 self.update_state(
                     |e: &mut InnerState| {
@@ -183,8 +319,7 @@ impl InboundRequest {
             }
             State::SentUkeyServerInit => {
                 debug!("Handling State::SentUkeyServerInit frame");
-                let msg = Ukey2Message::decode(&*frame_data)?;
-                self.process_ukey2_client_finish(&msg, &frame_data).await?;
+                self.process_ukey2_client_finish(&frame_data).await?;
 
                 self.update_state(
                     |e: &mut InnerState| {
@@ -209,144 +344,50 @@ impl InboundRequest {
             }
             _ => {
                 debug!("Handling SecureMessage frame");
-                let smsg = SecureMessage::decode(&*frame_data)?;
-                self.decrypt_and_process_secure_message(&smsg).await?;
+                self.decrypt_and_process_secure_message(&frame_data).await?;
             }
         }
 
         Ok(())
     }
 
-    fn process_connection_request(
-        &self,
-        frame: &location_nearby_connections::OfflineFrame,
-    ) -> Result<RemoteDeviceInfo, anyhow::Error> {
-        let v1_frame = frame
-            .v1
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
-
-        if v1_frame.r#type() != location_nearby_connections::v1_frame::FrameType::ConnectionRequest
-        {
-            return Err(anyhow!(format!(
-                "Unexpected frame type: {:?}",
-                v1_frame.r#type()
-            )));
-        }
-
-        let connection_request = v1_frame
-            .connection_request
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
-
-        let endpoint_info = connection_request
-            .endpoint_info
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing endpoint info"))?;
-
-        // Check if endpoint info length is greater than 17
-        if endpoint_info.len() <= 17 {
-            return Err(anyhow!("Endpoint info too short"));
-        }
-
-        let device_name_length = endpoint_info[17] as usize;
-        // Validate length including device name
-        if endpoint_info.len() < device_name_length + 18 {
-            return Err(anyhow!(
-                "Endpoint info too short to contain the device name"
-            ));
-        }
-
-        // Extract and validate device name based on length
-        let device_name = std::str::from_utf8(&endpoint_info[18..(18 + device_name_length)])
-            .map_err(|_| anyhow!("Device name is not valid UTF-8"))?;
-
-        // Parsing the device type
-        let raw_device_type = (endpoint_info[0] & 7) >> 1_usize;
-
-        Ok(RemoteDeviceInfo {
-            name: device_name.to_string(),
-            device_type: DeviceType::from_raw_value(raw_device_type),
-        })
-    }
-
-    async fn process_ukey2_client_init(&mut self, msg: &Ukey2Message) -> Result<(), anyhow::Error> {
-        if msg.message_type() != ukey2_message::Type::ClientInit {
-            self.send_ukey2_alert(AlertType::BadMessageType).await?;
-            return Err(anyhow!(
-                "UKey2: message_type({:?}) != ClientInit",
-                msg.message_type
-            ));
-        }
-
-        let client_init = match Ukey2ClientInit::decode(msg.message_data()) {
-            Ok(uk2ci) => uk2ci,
-            Err(e) => {
-                self.send_ukey2_alert(AlertType::BadMessageData).await?;
-                return Err(anyhow!("UKey2: Ukey2ClientInit::decode: {}", e));
+    async fn process_ukey2_client_init(&mut self, data: &[u8]) -> Result<(), anyhow::Error> {
+        let (client_init, commitment) = match parse_ukey2_client_init(data) {
+            Ok(r) => r,
+            Err(ProtocolError::Ukey2(alert)) => {
+                self.send_ukey2_alert(alert).await?;
+                return Err(ProtocolError::Ukey2(alert).into());
             }
+            Err(e) => return Err(e.into()),
         };
 
-        if client_init.version() != 1 {
-            self.send_ukey2_alert(AlertType::BadVersion).await?;
-            return Err(anyhow!("UKey2: client_init.version != 1"));
-        }
-
-        if client_init.random().len() != 32 {
-            self.send_ukey2_alert(AlertType::BadRandom).await?;
-            return Err(anyhow!("UKey2: client_init.random.len != 32"));
-        }
-
-        // Searching for preferred cipher commitment
-        let mut found = false;
         for commitment in &client_init.cipher_commitments {
             trace!("CipherCommitment: {:?}", commitment.handshake_cipher());
-            if Ukey2HandshakeCipher::P256Sha512 == commitment.handshake_cipher() {
-                found = true;
-                self.update_state(
-                    |e| {
-                        e.cipher_commitment = Some(commitment.clone());
-                    },
-                    false,
-                )
-                .await;
-                break;
-            }
-        }
-
-        if !found {
-            self.send_ukey2_alert(AlertType::BadHandshakeCipher).await?;
-            return Err(anyhow!("UKey2: badHandshakeCipher"));
-        }
-
-        if client_init.next_protocol() != "AES_256_CBC-HMAC_SHA256" {
-            self.send_ukey2_alert(AlertType::BadNextProtocol).await?;
-            return Err(anyhow!(
-                "UKey2: badNextProtocol: {}",
-                client_init.next_protocol()
-            ));
         }
+        let cipher = commitment.handshake_cipher();
+        debug!("Using handshake cipher {:?}", cipher);
+        self.update_state(
+            |e| {
+                e.cipher_commitment = Some(commitment);
+            },
+            false,
+        )
+        .await;
 
+        // Curve25519 keys are sent raw, P256 ones as a GenericPublicKey
         let (secret_key, public_key) = gen_ecdsa_keypair();
-
-        let encoded_point = public_key.to_encoded_point(false);
-        let x = encoded_point.x().unwrap();
-        let y = encoded_point.y().unwrap();
-
-        let pkey = GenericPublicKey {
-            r#type: PublicKeyType::EcP256.into(),
-            ec_p256_public_key: Some(EcP256PublicKey {
-                x: encode_point(Bytes::from(x.to_vec()))?,
-                y: encode_point(Bytes::from(y.to_vec()))?,
-            }),
-            ..Default::default()
+        let (x25519_secret_key, x25519_public_key) = gen_x25519_keypair();
+        let pkey = if cipher == Ukey2HandshakeCipher::Curve25519Sha512 {
+            x25519_public_key.as_bytes().to_vec()
+        } else {
+            encode_p256_public_key(&public_key)?
         };
 
         let server_init = Ukey2ServerInit {
             version: Some(1),
             random: Some(rand::rng().random::<[u8; 32]>().to_vec()),
-            handshake_cipher: Some(Ukey2HandshakeCipher::P256Sha512.into()),
-            public_key: Some(pkey.encode_to_vec()),
+            handshake_cipher: Some(cipher.into()),
+            public_key: Some(pkey),
         };
 
         let server_init_msg = Ukey2Message {
@@ -359,6 +400,7 @@ impl InboundRequest {
             |e| {
                 e.private_key = Some(secret_key);
                 e.public_key = Some(public_key);
+                e.x25519_private_key = Some(x25519_secret_key);
                 e.server_init_data = Some(server_init_data.clone());
             },
             false,
@@ -370,46 +412,22 @@ impl InboundRequest {
         Ok(())
     }
 
-    async fn process_ukey2_client_finish(
-        &mut self,
-        msg: &Ukey2Message,
-        frame_data: &Vec<u8>,
-    ) -> Result<(), anyhow::Error> {
-        if msg.message_type() != ukey2_message::Type::ClientFinish {
-            self.send_ukey2_alert(AlertType::BadMessageType).await?;
-            return Err(anyhow!(
-                "UKey2: message_type({:?}) != ClientFinish",
-                msg.message_type
-            ));
-        }
-
-        let sha512 = Sha512::digest(frame_data);
-        if self.state.cipher_commitment.as_ref().unwrap().commitment() != sha512.as_slice() {
-            error!("cipher_commitment isn't equals to sha512(frame_data)");
-            return Err(anyhow!("UKey2: cipher_commitment != sha512"));
-        }
-
-        let client_finish = match Ukey2ClientFinished::decode(msg.message_data()) {
-            Ok(uk2cf) => uk2cf,
-            Err(e) => {
-                return Err(anyhow!("UKey2: Ukey2ClientFinished::decode: {}", e));
-            }
-        };
-
-        if client_finish.public_key.is_none() {
-            return Err(anyhow!("UKey2: client_finish.public_key None"));
-        }
-
-        let client_public_key = match GenericPublicKey::decode(client_finish.public_key()) {
-            Ok(cpk) => cpk,
-            Err(e) => {
-                return Err(anyhow!("UKey2: GenericPublicKey::decode: {}", e));
+    async fn process_ukey2_client_finish(&mut self, data: &[u8]) -> Result<(), anyhow::Error> {
+        let commitment = self
+            .state
+            .cipher_commitment
+            .as_ref()
+            .ok_or_else(|| anyhow!("No handshake cipher negotiated"))?;
+        let public_key = match parse_ukey2_client_finish(data, commitment) {
+            Ok(k) => k,
+            Err(ProtocolError::Ukey2(alert)) => {
+                self.send_ukey2_alert(alert).await?;
+                return Err(ProtocolError::Ukey2(alert).into());
             }
+            Err(e) => return Err(e.into()),
         };
 
-        self.finalize_key_exchange(client_public_key).await?;
-
-        Ok(())
+        self.finalize_key_exchange(&public_key).await
     }
 
     async fn process_connection_response(
@@ -461,45 +479,113 @@ impl InboundRequest {
 
         self.send_encrypted_frame(&paired_encryption).await?;
 
+        // Like Nearby Connections, the receiving side initiates the upgrade
+        if self.remote_supports_wifi_lan {
+            self.offer_upgrade().await?;
+        }
+
         Ok(())
     }
 
-    async fn decrypt_and_process_secure_message(
+    async fn offer_upgrade(&mut self) -> Result<(), anyhow::Error> {
+        // Not every transport can be swapped for a TCP socket
+        let Some(local) = self.socket.local_addr() else {
+            return Ok(());
+        };
+
+        let listener = match listen_wifi_lan(local).await {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Couldn't listen for a bandwidth upgrade: {e}");
+                return Ok(());
+            }
+        };
+
+        let addr = listener.local_addr()?;
+        info!("Offering a WIFI_LAN upgrade on {addr}");
+        self.encrypt_and_send(&bwu_frame(
+            EventType::UpgradePathAvailable,
+            Some(wifi_lan_path(&addr)),
+        ))
+        .await?;
+        self.bwu.listener = Some(listener);
+
+        Ok(())
+    }
+
+    async fn process_upgrade_connection(
         &mut self,
-        smsg: &SecureMessage,
+        mut socket: TcpStream,
     ) -> Result<(), anyhow::Error> {
-        let mut hmac = HmacSha256::new_from_slice(self.state.recv_hmac_key.as_ref().unwrap())?;
-        hmac.update(&smsg.header_and_body);
-        if !hmac
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .eq(smsg.signature.as_slice())
-        {
-            return Err(anyhow!("hmac!=signature"));
+        // Only one attempt, the sender tells us if it failed on its side
+        self.bwu.listener = None;
+
+        if let Err(e) = accept_introduction(&mut socket, self.remote_endpoint_id.as_deref()).await {
+            warn!("Bandwidth upgrade failed: {e}");
+            return Ok(());
         }
 
-        let header_and_body = HeaderAndBody::decode(&*smsg.header_and_body)?;
+        let Some(socket) = S::from_upgraded(socket) else {
+            warn!("Bandwidth upgrade failed: transport can't be upgraded");
+            return Ok(());
+        };
 
-        let msg_data = header_and_body.body;
-        let key = self.state.decrypt_key.as_ref().unwrap();
+        debug!("Upgraded socket introduced, draining the prior one");
+        self.bwu.socket = Some(socket);
+        self.encrypt_and_send(&bwu_frame(EventType::LastWriteToPriorChannel, None))
+            .await
+    }
 
-        let mut cipher = Cipher::new_256(key[..AES_256_KEY_LEN].try_into()?);
-        cipher.set_auto_padding(true);
-        let decrypted = cipher.cbc_decrypt(header_and_body.header.iv(), &msg_data);
+    async fn process_bandwidth_upgrade(
+        &mut self,
+        bwu: &BandwidthUpgradeNegotiationFrame,
+    ) -> Result<(), anyhow::Error> {
+        debug!("Received bandwidth upgrade event: {:?}", bwu.event_type());
+        match bwu.event_type() {
+            EventType::LastWriteToPriorChannel => {
+                self.encrypt_and_send(&bwu_frame(EventType::SafeToClosePriorChannel, None))
+                    .await?;
+                if !self.bwu.switch_writes(&mut self.socket) {
+                    warn!("Prior channel closing without an upgraded one");
+                }
+            }
+            EventType::SafeToClosePriorChannel => {
+                if self.bwu.switch_reads() {
+                    info!("Bandwidth upgrade done");
+                }
+            }
+            EventType::UpgradeFailure => {
+                warn!("The sender couldn't upgrade the connection");
+                self.bwu.listener = None;
+                self.bwu.socket = None;
+            }
+            _ => {
+                warn!("Unexpected bandwidth upgrade event: {:?}", bwu.event_type());
+            }
+        }
 
-        let d2d_msg = DeviceToDeviceMessage::decode(&*decrypted)?;
+        Ok(())
+    }
 
-        let seq = self.get_client_seq_inc().await;
-        if d2d_msg.sequence_number() != seq {
-            return Err(anyhow!(
-                "Error d2d_msg.sequence_number invalid ({} vs {})",
-                d2d_msg.sequence_number(),
-                seq
-            ));
-        }
+    async fn decrypt_and_process_secure_message(
+        &mut self,
+        data: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let message = match self
+            .state
+            .secure_channel
+            .as_mut()
+            .ok_or_else(|| anyhow!("Encrypted frame before the end of the handshake"))?
+            .decrypt(data)
+        {
+            Ok(m) => m,
+            Err(e) => match e.downcast_ref::<SecureChannelError>() {
+                Some(sce) => return self.security_error(sce).await,
+                None => return Err(e),
+            },
+        };
 
-        let offline = location_nearby_connections::OfflineFrame::decode(d2d_msg.message())?;
+        let offline = location_nearby_connections::OfflineFrame::decode(&*message)?;
         let v1_frame = offline
             .v1
             .as_ref()
@@ -516,6 +602,14 @@ impl InboundRequest {
                     .payload_header
                     .as_ref()
                     .ok_or_else(|| anyhow!("Missing required fields"))?;
+                if payload_transfer.packet_type() == PacketType::Control {
+                    let control = payload_transfer
+                        .control_message
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("Missing required fields"))?;
+                    return self.process_control_message(header, control).await;
+                }
+
                 let chunk = payload_transfer
                     .payload_chunk
                     .as_ref()
@@ -525,97 +619,29 @@ impl InboundRequest {
                     payload_header::PayloadType::Bytes => {
                         info!("Processing PayloadType::Bytes");
                         let payload_id = header.id();
-
-                        if header.total_size() > SANE_FRAME_LENGTH.into() {
-                            self.state.payload_buffers.remove(&payload_id);
-                            return Err(anyhow!(
-                                "Payload too large: {} bytes",
-                                header.total_size()
-                            ));
+                        if self.dropped_payloads.contains_key(&payload_id) {
+                            trace!("Ignoring chunk of dropped payload {payload_id}");
+                            return Ok(());
                         }
 
-                        self.state
-                            .payload_buffers
-                            .entry(payload_id)
-                            .or_insert_with(|| Vec::with_capacity(header.total_size() as usize));
-
-                        // Get the current length of the buffer, if it exists, without holding a mutable borrow.
-                        let buffer_len = self.state.payload_buffers.get(&payload_id).unwrap().len();
-                        if chunk.offset() != buffer_len as i64 {
+                        let buffer = self.state.payload_buffers.entry(payload_id).or_default();
+                        if let Err(e) = append_bytes_chunk(buffer, header.total_size(), chunk) {
                             self.state.payload_buffers.remove(&payload_id);
-                            return Err(anyhow!(
-                                "Unexpected chunk offset: {}, expected: {}",
-                                chunk.offset(),
-                                buffer_len
-                            ));
-                        }
-
-                        let buffer = self.state.payload_buffers.get_mut(&payload_id).unwrap();
-                        if let Some(body) = &chunk.body {
-                            buffer.extend(body);
+                            return Err(e.into());
                         }
 
                         if (chunk.flags() & 1) == 1 {
                             debug!("Chunk flags & 1 == 1 ?? End of data ??");
-
-                            if self.state.text_payload.is_some()
-                                && self.state.text_payload.as_ref().unwrap().get_i64_value()
-                                    == payload_id
+                            let buffer = self
+                                .state
+                                .payload_buffers
+                                .remove(&payload_id)
+                                .unwrap_or_default();
+
+                            if self.state.state == State::ReceivingFiles
+                                && self.state.text_payloads.contains_key(&payload_id)
                             {
-                                info!("Transfer finished");
-                                let end_index =
-                                    buffer.iter().position(|&b| b == 16).unwrap_or(buffer.len());
-                                let payload = std::str::from_utf8(&buffer[..end_index])?.to_owned();
-
-                                match self.state.text_payload.clone().unwrap() {
-                                    TextPayloadInfo::Url(_) => {
-                                        self.update_state(
-                                            |e| {
-                                                if let Some(tmd) = e.transfer_metadata.as_mut() {
-                                                    tmd.text_payload = Some(payload);
-                                                    tmd.text_type = Some(TextPayloadType::Url);
-                                                }
-                                            },
-                                            false,
-                                        )
-                                        .await;
-                                    }
-                                    TextPayloadInfo::Text(_) => {
-                                        self.update_state(
-                                            |e| {
-                                                if let Some(tmd) = e.transfer_metadata.as_mut() {
-                                                    tmd.text_payload = Some(payload);
-                                                    tmd.text_type = Some(TextPayloadType::Text);
-                                                }
-                                            },
-                                            false,
-                                        )
-                                        .await;
-                                    }
-                                    TextPayloadInfo::Wifi((_, ssid)) => {
-                                        self.update_state(
-                                            |e| {
-                                                if let Some(tmd) = e.transfer_metadata.as_mut() {
-                                                    tmd.text_payload =
-                                                        Some(format!("{ssid}: {}", payload.trim()));
-                                                    tmd.text_type = Some(TextPayloadType::Wifi);
-                                                }
-                                            },
-                                            false,
-                                        )
-                                        .await;
-                                    }
-                                }
-
-                                self.update_state(
-                                    |e| {
-                                        e.state = State::Finished;
-                                    },
-                                    true,
-                                )
-                                .await;
-                                self.disconnection().await?;
-                                return Err(anyhow!(crate::errors::AppError::NotAnError));
+                                self.process_text_payload(payload_id, &buffer).await?;
                             } else {
                                 let innner_frame =
                                     sharing_nearby::Frame::decode(buffer.as_slice())?;
@@ -625,67 +651,11 @@ impl InboundRequest {
                     }
                     payload_header::PayloadType::File => {
                         info!("Processing PayloadType::File");
-                        let payload_id = header.id();
-
-                        let file_internal = self
-                            .state
-                            .transferred_files
-                            .get_mut(&payload_id)
-                            .ok_or_else(|| {
-                                anyhow!("File payload ID ({}) is not known", payload_id)
-                            })?;
-
-                        let current_offset = file_internal.bytes_transferred;
-                        if chunk.offset() != current_offset {
-                            return Err(anyhow!(
-                                "Invalid offset into file {}, expected {}",
-                                chunk.offset(),
-                                current_offset
-                            ));
-                        }
-
-                        let chunk_size = chunk.body().len();
-                        if current_offset + chunk_size as i64 > file_internal.total_size {
-                            return Err(anyhow!(
-                                "Transferred file size exceeds previously specified value: {} vs {}", current_offset + chunk_size as i64, file_internal.total_size
-                            ));
-                        }
-
-                        if !chunk.body().is_empty() {
-                            file_internal
-                                .file
-                                .as_ref()
-                                .unwrap()
-                                .write_all_at(chunk.body(), current_offset as u64)?;
-                            file_internal.bytes_transferred += chunk_size as i64;
-
-                            self.update_state(
-                                |e| {
-                                    if let Some(tmd) = e.transfer_metadata.as_mut() {
-                                        tmd.ack_bytes += chunk_size as u64;
-                                    }
-                                },
-                                true,
-                            )
-                            .await;
-                        } else if (chunk.flags() & 1) == 1 {
-                            self.state.transferred_files.remove(&payload_id);
-                            if self.state.transferred_files.is_empty() {
-                                info!("Transfer finished");
-                                self.update_state(
-                                    |e| {
-                                        e.state = State::Finished;
-                                    },
-                                    true,
-                                )
-                                .await;
-                                self.disconnection().await?;
-                                return Err(anyhow!(crate::errors::AppError::NotAnError));
-                            }
-                        }
+                        self.process_file_chunk(header.id(), chunk, true).await?;
                     }
                     payload_header::PayloadType::Stream => {
-                        error!("Unhandled PayloadType::Stream: {:?}", header.r#type())
+                        info!("Processing PayloadType::Stream");
+                        self.process_stream_chunk(header, chunk).await?;
                     }
                     payload_header::PayloadType::UnknownPayloadType => {
                         error!(
@@ -695,18 +665,422 @@ impl InboundRequest {
                     }
                 }
             }
-            location_nearby_connections::v1_frame::FrameType::KeepAlive => {
-                trace!("Sending keepalive");
-                self.send_keepalive(true).await?;
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation => {
+                let bwu = v1_frame
+                    .bandwidth_upgrade_negotiation
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Missing required fields"))?;
+                self.process_bandwidth_upgrade(bwu).await?;
+            }
+            location_nearby_connections::v1_frame::FrameType::KeepAlive => {
+                // Acks aren't answered, or both sides would ack each other forever
+                if !v1_frame.keep_alive.as_ref().is_some_and(|k| k.ack()) {
+                    trace!("Sending keepalive");
+                    self.send_keepalive(true).await?;
+                }
+            }
+            location_nearby_connections::v1_frame::FrameType::Disconnection => {
+                let disconnection = v1_frame.disconnection.unwrap_or_default();
+                return self.process_remote_disconnection(&disconnection).await;
+            }
+            _ => {
+                error!("Unhandled offline frame encrypted: {:?}", offline);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn process_text_payload(
+        &mut self,
+        payload_id: i64,
+        buffer: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let text_info = self
+            .state
+            .text_payloads
+            .remove(&payload_id)
+            .ok_or_else(|| anyhow!("Text payload ID ({}) is not known", payload_id))?;
+
+        let payload = match &text_info {
+            TextPayloadInfo::Url(_) | TextPayloadInfo::Text(_) => {
+                std::str::from_utf8(buffer)?.to_owned()
+            }
+            TextPayloadInfo::Wifi((_, ssid)) => {
+                let credentials = WifiCredentials::decode(buffer)?;
+                format!("{ssid}: {}", credentials.password())
+            }
+        };
+        info!("Text payload ID ({}) received", payload_id);
+
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    // The legacy text fields mirror the first text item
+                    let first_text = tmd.items.iter().find(|i| i.text_type.is_some());
+                    if first_text.map(|i| i.payload_id) == Some(payload_id) {
+                        tmd.text_type = Some(text_info.get_type());
+                        tmd.text_payload = Some(payload.clone());
+                    }
+
+                    if let Some(item) = tmd.items.iter_mut().find(|i| i.payload_id == payload_id) {
+                        item.text_payload = Some(payload);
+                    }
+                    tmd.ack_bytes += buffer.len() as u64;
+                }
+            },
+            true,
+        )
+        .await;
+
+        self.check_transfer_finished().await
+    }
+
+    async fn process_file_chunk(
+        &mut self,
+        payload_id: i64,
+        chunk: &PayloadChunk,
+        sized: bool,
+    ) -> Result<(), anyhow::Error> {
+        if self.dropped_payloads.contains_key(&payload_id) {
+            trace!("Ignoring chunk of dropped payload {payload_id}");
+            return Ok(());
+        }
+
+        let file_internal = self
+            .state
+            .transferred_files
+            .get_mut(&payload_id)
+            .ok_or_else(|| anyhow!("File payload ID ({}) is not known", payload_id))?;
+
+        let current_offset = file_internal.bytes_transferred;
+        if chunk.offset() != current_offset {
+            return Err(anyhow!(
+                "Invalid offset into file {}, expected {}",
+                chunk.offset(),
+                current_offset
+            ));
+        }
+
+        let chunk_size = chunk.body().len();
+        if sized && current_offset + chunk_size as i64 > file_internal.total_size {
+            return Err(anyhow!(
+                "Transferred file size exceeds previously specified value: {} vs {}",
+                current_offset + chunk_size as i64,
+                file_internal.total_size
+            ));
+        }
+
+        if !chunk.body().is_empty() {
+            let written = match file_internal.file.as_ref() {
+                Some(file) => file.write_all_at(chunk.body(), current_offset as u64),
+                None => Err(std::io::Error::other("file is not opened")),
+            };
+            if let Err(e) = written {
+                // Only this file is lost, let the sender know and go on
+                error!("Failed to write {:?}: {}", file_internal.file_url, e);
+                self.send_control_message(
+                    payload_id,
+                    payload_header::PayloadType::File,
+                    control_message::EventType::PayloadError,
+                    current_offset,
+                )
+                .await?;
+                self.drop_payload(payload_id).await;
+                return self.check_transfer_finished().await;
+            }
+            file_internal.bytes_transferred += chunk_size as i64;
+
+            self.update_state(
+                |e| {
+                    if let Some(tmd) = e.transfer_metadata.as_mut() {
+                        tmd.ack_bytes += chunk_size as u64;
+                    }
+                },
+                true,
+            )
+            .await;
+        }
+
+        // The sender's progress follows the acks, which don't need to be
+        // as frequent as the chunks
+        let last_chunk = (chunk.flags() & 1) == 1;
+        let received = current_offset + chunk_size as i64;
+        if last_chunk || received / ACK_INTERVAL_BYTES != current_offset / ACK_INTERVAL_BYTES {
+            self.send_control_message(
+                payload_id,
+                payload_header::PayloadType::File,
+                control_message::EventType::PayloadReceivedAck,
+                received,
+            )
+            .await?;
+        }
+
+        if last_chunk {
+            if let Some(mut mfi) = self.state.transferred_files.remove(&payload_id) {
+                finalize_file(&mut mfi, &self.resume)?;
+            }
+            self.check_transfer_finished().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_stream_chunk(
+        &mut self,
+        header: &PayloadHeader,
+        chunk: &PayloadChunk,
+    ) -> Result<(), anyhow::Error> {
+        if self.state.state != State::ReceivingFiles {
+            return Err(anyhow!(
+                "Stream payload received before consent: {:?}",
+                self.state.state
+            ));
+        }
+
+        let payload_id = header.id();
+        if self.dropped_payloads.contains_key(&payload_id) {
+            trace!("Ignoring chunk of dropped payload {payload_id}");
+            return Ok(());
+        }
+
+        // Streams announced as a file in the introduction (or for which
+        // nobody is listening) are written into the download directory.
+        if self.state.transferred_files.contains_key(&payload_id) {
+            return self.process_file_chunk(payload_id, chunk, false).await;
+        }
+
+        if !self.state.streams.contains_key(&payload_id) {
+            if chunk.offset() != 0 {
+                return Err(anyhow!(
+                    "Stream payload ID ({}) started at offset {}",
+                    payload_id,
+                    chunk.offset()
+                ));
+            }
+
+            let name = header.file_name.clone();
+            let (writer, reader) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+            // Never waiting on the consumer, which would block the whole
+            // connection (keepalives included) if it doesn't take them.
+            let forwarded = match &self.stream_sender {
+                Some(stream_sender) => match stream_sender.try_send(InboundStream::new(
+                    self.state.id.clone(),
+                    payload_id,
+                    name.clone(),
+                    reader,
+                )) {
+                    Ok(()) => true,
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        warn!("Inbound streams aren't taken, writing {payload_id} to a file");
+                        false
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => false,
+                },
+                None => false,
+            };
+
+            if forwarded {
+                self.state.streams.insert(payload_id, writer);
+            } else {
+                let dest = get_destination(
+                    self.download_dir(),
+                    header.parent_folder.as_deref(),
+                    &name.unwrap_or_else(|| format!("stream_{}", payload_id as u64)),
+                );
+                create_parent_dirs(&dest)?;
+                info!("Stream destination: {:?}", dest);
+                let file = self.resume.create_part(&dest, payload_id)?;
+                self.state.transferred_files.insert(
+                    payload_id,
+                    InternalFileInfo {
+                        payload_id,
+                        file_url: dest,
+                        bytes_transferred: 0,
+                        total_size: header.total_size(),
+                        file: Some(file),
+                        parent_folder: header.parent_folder.clone(),
+                        resume_key: None,
+                    },
+                );
+
+                return self.process_file_chunk(payload_id, chunk, false).await;
+            }
+        }
+
+        let chunk_size = chunk.body().len();
+        if chunk_size > 0 {
+            let writer = self
+                .state
+                .streams
+                .get_mut(&payload_id)
+                .ok_or_else(|| anyhow!("Stream payload ID ({}) is not known", payload_id))?;
+
+            let written =
+                tokio::time::timeout(STREAM_STALL_TIMEOUT, writer.write_all(chunk.body()))
+                    .await
+                    .map_err(|_| anyhow!("the reader stopped reading"))
+                    .and_then(|r| r.map_err(|e| anyhow!("the reader is gone: {e}")));
+            if let Err(e) = written {
+                // Nothing to deliver to anymore, the other payloads go on
+                warn!("Stream payload ID ({}) cancelled: {}", payload_id, e);
+                self.send_control_message(
+                    payload_id,
+                    payload_header::PayloadType::Stream,
+                    control_message::EventType::PayloadCanceled,
+                    chunk.offset(),
+                )
+                .await?;
+                self.drop_payload(payload_id).await;
+                return self.check_transfer_finished().await;
+            }
+
+            self.update_state(
+                |e| {
+                    if let Some(tmd) = e.transfer_metadata.as_mut() {
+                        tmd.ack_bytes += chunk_size as u64;
+                    }
+                },
+                true,
+            )
+            .await;
+        }
+
+        if (chunk.flags() & 1) == 1 {
+            // Dropping the writer signals EOF to the reader
+            if let Some(mut writer) = self.state.streams.remove(&payload_id) {
+                let _ = writer.shutdown().await;
+            }
+            self.check_transfer_finished().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_control_message(
+        &mut self,
+        payload_id: i64,
+        payload_type: payload_header::PayloadType,
+        event: control_message::EventType,
+        offset: i64,
+    ) -> Result<(), anyhow::Error> {
+        let wrapper = location_nearby_connections::OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(location_nearby_connections::V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
+                ),
+                payload_transfer: Some(PayloadTransferFrame {
+                    packet_type: Some(PacketType::Control.into()),
+                    payload_header: Some(PayloadHeader {
+                        id: Some(payload_id),
+                        r#type: Some(payload_type.into()),
+                        ..Default::default()
+                    }),
+                    control_message: Some(ControlMessage {
+                        event: Some(event.into()),
+                        offset: Some(offset),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        };
+
+        self.encrypt_and_send(&wrapper).await
+    }
+
+    async fn process_control_message(
+        &mut self,
+        header: &PayloadHeader,
+        control: &ControlMessage,
+    ) -> Result<(), anyhow::Error> {
+        let payload_id = header.id();
+
+        match control.event() {
+            control_message::EventType::PayloadCanceled
+            | control_message::EventType::PayloadError => {
+                warn!(
+                    "Payload {payload_id} stopped by the sender: {:?}",
+                    control.event()
+                );
+                self.drop_payload(payload_id).await;
+                if self.state.state == State::ReceivingFiles {
+                    return self.check_transfer_finished().await;
+                }
+            }
+            control_message::EventType::PayloadReceivedAck => {
+                trace!("Payload {payload_id} acked up to {}", control.offset());
             }
-            _ => {
-                error!("Unhandled offline frame encrypted: {:?}", offline);
+            control_message::EventType::UnknownEventType => {
+                warn!("Unknown control message for payload {payload_id}");
             }
         }
 
         Ok(())
     }
 
+    // Stop receiving a single payload, the others are still received
+    fn payload_type(&self, payload_id: i64) -> payload_header::PayloadType {
+        if self.state.streams.contains_key(&payload_id) {
+            payload_header::PayloadType::Stream
+        } else if self.state.text_payloads.contains_key(&payload_id) {
+            payload_header::PayloadType::Bytes
+        } else {
+            payload_header::PayloadType::File
+        }
+    }
+
+    async fn drop_payload(&mut self, payload_id: i64) {
+        self.dropped_payloads
+            .insert(payload_id, self.payload_type(payload_id));
+        self.state.payload_buffers.remove(&payload_id);
+        self.state.text_payloads.remove(&payload_id);
+        if let Some(mut writer) = self.state.streams.remove(&payload_id) {
+            let _ = writer.shutdown().await;
+        }
+
+        let Some(mfi) = self.state.transferred_files.remove(&payload_id) else {
+            return;
+        };
+        self.resume.remove_part(&mfi);
+
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.total_bytes = tmd
+                        .total_bytes
+                        .saturating_sub((mfi.total_size - mfi.bytes_transferred) as u64);
+                }
+            },
+            true,
+        )
+        .await;
+    }
+
+    async fn check_transfer_finished(&mut self) -> Result<(), anyhow::Error> {
+        if self.state.state == State::Finished
+            || !self.state.transferred_files.is_empty()
+            || !self.state.streams.is_empty()
+            || !self.state.text_payloads.is_empty()
+        {
+            return Ok(());
+        }
+
+        info!("Transfer finished");
+        self.update_state(
+            |e| {
+                e.state = State::Finished;
+            },
+            true,
+        )
+        .await;
+
+        // Give the sender a chance to ask for a safe disconnection
+        self.disconnect_deadline = Some(Instant::now() + SAFE_TO_DISCONNECT_TIMEOUT);
+        Ok(())
+    }
+
     async fn process_transfer_setup(
         &mut self,
         frame: &sharing_nearby::Frame,
@@ -717,15 +1091,8 @@ impl InboundRequest {
             .ok_or_else(|| anyhow!("Missing required fields"))?;
 
         if v1_frame.r#type() == sharing_nearby::v1_frame::FrameType::Cancel {
-            info!("Transfer canceled");
-            self.update_state(
-                |e| {
-                    e.state = State::Cancelled;
-                },
-                true,
-            )
-            .await;
-            self.disconnection().await?;
+            info!("Transfer canceled by the remote device");
+            self.cancel(CancelOrigin::Remote).await?;
             return Err(anyhow!(crate::errors::AppError::NotAnError));
         }
 
@@ -820,173 +1187,255 @@ impl InboundRequest {
         )
         .await;
 
-        if !introduction.file_metadata.is_empty() && introduction.text_metadata.is_empty() {
-            trace!("process_introduction: handling file_metadata");
-            let mut files_name = Vec::with_capacity(introduction.file_metadata.len());
-            let mut total_bytes: u64 = 0;
-
-            for file in &introduction.file_metadata {
-                info!("File name: {}", file.name());
+        let mut files_name = Vec::with_capacity(introduction.file_metadata.len());
+        let mut items = Vec::new();
+        let mut total_bytes: u64 = 0;
 
-                let mut dest = get_download_dir();
-                dest.push(file.name());
-
-                info!("Destination: {:?}", dest);
-                if dest.exists() {
-                    let mut counter = 1;
-                    dest.pop();
+        // Other senders change the attachment ids on each connection, what
+        // they sent is never offered again and isn't worth keeping.
+        let supports_resume = introduction
+            .rquickshare_extension
+            .as_ref()
+            .is_some_and(|ext| ext.supports_resume());
+        for file in &introduction.file_metadata {
+            info!("File name: {}", file.name());
+            if file.size() < 0 {
+                return Err(ProtocolError::InvalidSize(file.size()).into());
+            }
 
-                    loop {
-                        dest.push(format!("{}_{}", counter, file.name()));
-                        if !dest.exists() {
-                            break;
-                        }
-                        dest.pop();
-                        counter += 1;
+            let rkey = self
+                .state
+                .remote_device_info
+                .as_ref()
+                .filter(|_| supports_resume)
+                .and_then(|rdi| resume_key(&rdi.name, file.id(), file.size()));
+
+            let info = match rkey
+                .as_deref()
+                .and_then(|k| self.resume.take(k, file.payload_id()))
+            {
+                Some(info) => {
+                    info!(
+                        "Resuming {:?} at {} bytes",
+                        info.file_url, info.bytes_transferred
+                    );
+                    info
+                }
+                None => {
+                    let dest = get_destination(
+                        self.download_dir(),
+                        file.parent_folder.as_deref(),
+                        file.name(),
+                    );
+                    info!("Destination: {:?}", dest);
+
+                    InternalFileInfo {
+                        payload_id: file.payload_id(),
+                        file_url: dest,
+                        bytes_transferred: 0,
+                        total_size: file.size(),
+                        file: None,
+                        parent_folder: file.parent_folder.clone(),
+                        resume_key: rkey,
                     }
-
-                    info!("New destination: {:?}", dest);
                 }
-
-                let info = InternalFileInfo {
-                    payload_id: file.payload_id(),
-                    file_url: dest,
-                    bytes_transferred: 0,
-                    total_size: file.size(),
-                    file: None,
-                };
-                total_bytes += info.total_size as u64;
-                self.state.transferred_files.insert(file.payload_id(), info);
-                files_name.push(file.name().to_owned());
-            }
-
-            let metadata = TransferMetadata {
-                id: self.state.id.clone(),
-                destination: Some(
-                    get_download_dir()
-                        .into_os_string()
-                        .into_string()
-                        .map_err(|_| anyhow!("failed to convert PathBuf to String"))?,
-                ),
-                source: self.state.remote_device_info.clone(),
-                files: Some(files_name),
-                pin_code: self.state.pin_code.clone(),
-                text_description: None,
-                total_bytes,
-                ..Default::default()
             };
+            total_bytes = total_bytes.saturating_add(info.total_size as u64);
+            // Show where the file will be put, relative to the download dir
+            let display_name = info
+                .file_url
+                .strip_prefix(self.download_dir())
+                .unwrap_or(&info.file_url)
+                .to_string_lossy()
+                .into_owned();
+            self.state.transferred_files.insert(file.payload_id(), info);
+            files_name.push(display_name.clone());
+            items.push(TransferItem {
+                payload_id: file.payload_id(),
+                name: display_name,
+                size: file.size(),
+                text_type: None,
+                text_payload: None,
+            });
+        }
 
-            info!("Asking for user consent: {:?}", metadata);
-            self.update_state(
-                |e| {
-                    e.transfer_metadata = Some(metadata);
-                },
-                true,
-            )
-            .await;
-        } else if introduction.text_metadata.len() == 1 {
-            trace!("process_introduction: handling text_metadata");
-            let meta = introduction.text_metadata.first().unwrap();
-
-            match meta.r#type() {
-                text_metadata::Type::Url => {
-                    let metadata = TransferMetadata {
-                        id: self.state.id.clone(),
-                        destination: None,
-                        source: self.state.remote_device_info.clone(),
-                        files: None,
-                        pin_code: self.state.pin_code.clone(),
-                        text_description: meta.text_title.clone(),
-                        ..Default::default()
-                    };
-
-                    info!("Asking for user consent: {:?}", metadata);
-                    self.update_state(
-                        |e| {
-                            e.text_payload = Some(TextPayloadInfo::Url(meta.payload_id()));
-                            e.transfer_metadata = Some(metadata);
-                        },
-                        true,
-                    )
-                    .await;
-                }
+        for meta in &introduction.text_metadata {
+            let text_info = match meta.r#type() {
+                text_metadata::Type::Url => TextPayloadInfo::Url(meta.payload_id()),
                 text_metadata::Type::PhoneNumber
                 | text_metadata::Type::Address
-                | text_metadata::Type::Text => {
-                    let metadata = TransferMetadata {
-                        id: self.state.id.clone(),
-                        destination: None,
-                        source: self.state.remote_device_info.clone(),
-                        files: None,
-                        pin_code: self.state.pin_code.clone(),
-                        text_description: meta.text_title.clone(),
-                        ..Default::default()
-                    };
-
-                    info!("Asking for user consent: {:?}", metadata);
-                    self.update_state(
-                        |e| {
-                            e.text_payload = Some(TextPayloadInfo::Text(meta.payload_id()));
-                            e.transfer_metadata = Some(metadata);
-                        },
-                        true,
-                    )
-                    .await;
-                }
+                | text_metadata::Type::Text => TextPayloadInfo::Text(meta.payload_id()),
                 text_metadata::Type::Unknown => {
-                    // Reject transfer
-                    self.reject_transfer(Some(
-						sharing_nearby::connection_response_frame::Status::UnsupportedAttachmentType,
-					))
-					.await?;
+                    warn!("Ignoring unknown text_metadata: {:?}", meta);
+                    continue;
                 }
-            }
-        } else if introduction.wifi_credentials_metadata.len() == 1 {
-            trace!("process_introduction: handling wifi_credentials_metadata");
-            let meta = introduction.wifi_credentials_metadata.first().unwrap();
-
-            let metadata = TransferMetadata {
-                id: self.state.id.clone(),
-                destination: None,
-                source: self.state.remote_device_info.clone(),
-                files: None,
-                pin_code: self.state.pin_code.clone(),
-                text_description: meta.ssid.clone(),
-                ..Default::default()
             };
 
-            self.update_state(
-                |e| {
-                    e.text_payload = Some(TextPayloadInfo::Wifi((
-                        meta.payload_id(),
-                        meta.ssid().to_owned(),
-                    )));
-                    e.transfer_metadata = Some(metadata);
-                },
-                true,
-            )
-            .await;
-        } else {
+            total_bytes = total_bytes.saturating_add(meta.size().max(0) as u64);
+            items.push(TransferItem {
+                payload_id: meta.payload_id(),
+                name: meta.text_title().to_owned(),
+                size: meta.size(),
+                text_type: Some(text_info.get_type()),
+                text_payload: None,
+            });
+            self.state
+                .text_payloads
+                .insert(meta.payload_id(), text_info);
+        }
+
+        for meta in &introduction.wifi_credentials_metadata {
+            items.push(TransferItem {
+                payload_id: meta.payload_id(),
+                name: meta.ssid().to_owned(),
+                size: 0,
+                text_type: Some(TextPayloadType::Wifi),
+                text_payload: None,
+            });
+            self.state.text_payloads.insert(
+                meta.payload_id(),
+                TextPayloadInfo::Wifi((meta.payload_id(), meta.ssid().to_owned())),
+            );
+        }
+
+        if items.is_empty() {
             // Reject transfer
             self.reject_transfer(Some(
                 sharing_nearby::connection_response_frame::Status::UnsupportedAttachmentType,
             ))
             .await?;
+            return Ok(());
+        }
+
+        let destination = if files_name.is_empty() {
+            None
+        } else {
+            Some(
+                self.download_dir()
+                    .into_os_string()
+                    .into_string()
+                    .map_err(|_| anyhow!("failed to convert PathBuf to String"))?,
+            )
+        };
+        // Keep the single text fields filled for frontends only handling one text
+        let first_text = items.iter().find(|i| i.text_type.is_some());
+
+        let metadata = TransferMetadata {
+            id: self.state.id.clone(),
+            destination,
+            source: self.state.remote_device_info.clone(),
+            files: if files_name.is_empty() {
+                None
+            } else {
+                Some(files_name)
+            },
+            pin_code: self.state.pin_code.clone(),
+            text_type: first_text.and_then(|i| i.text_type.clone()),
+            text_description: first_text.map(|i| i.name.clone()),
+            total_bytes,
+            items,
+            ..Default::default()
+        };
+
+        if !self.has_enough_space() {
+            self.state.transfer_metadata = Some(metadata);
+            return self.reject_not_enough_space().await;
         }
 
+        info!("Asking for user consent: {:?}", metadata);
+        self.consent_deadline = Some(Instant::now() + get_consent_timeout(&self.consent_timeout));
+        self.update_state(
+            |e| {
+                e.transfer_metadata = Some(metadata);
+            },
+            true,
+        )
+        .await;
+
         Ok(())
     }
 
+    async fn cancel(&mut self, origin: CancelOrigin) -> Result<(), anyhow::Error> {
+        self.update_state(
+            |e| {
+                e.state = State::Cancelled;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.cancelled_by = Some(origin);
+                }
+            },
+            true,
+        )
+        .await;
+
+        self.disconnection().await
+    }
+
+    async fn process_remote_disconnection(
+        &mut self,
+        disconnection: &DisconnectionFrame,
+    ) -> Result<(), anyhow::Error> {
+        if disconnection.request_safe_to_disconnect() {
+            debug!("Acking the safe-to-disconnect request");
+            let _ = self
+                .send_disconnection(DisconnectionFrame {
+                    ack_safe_to_disconnect: Some(true),
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        if self.state.state == State::Finished {
+            debug!("Remote device disconnected");
+            return Err(anyhow!(crate::errors::AppError::NotAnError));
+        }
+
+        // The remote is already gone, no need to send it our disconnection
+        info!("Transfer canceled, the remote device disconnected");
+        self.update_state(
+            |e| {
+                e.state = State::Cancelled;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.cancelled_by = Some(CancelOrigin::Remote);
+                }
+            },
+            true,
+        )
+        .await;
+
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
+    async fn security_error(&mut self, err: &SecureChannelError) -> Result<(), anyhow::Error> {
+        error!("Security error, disconnecting: {err}");
+        // The frontend only knows about the transfer once it has metadata
+        let inform = self.state.transfer_metadata.is_some();
+        self.update_state(
+            |e| {
+                e.state = State::SecurityError;
+            },
+            inform,
+        )
+        .await;
+        let _ = self.disconnection().await;
+
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
     async fn disconnection(&mut self) -> Result<(), anyhow::Error> {
+        self.send_disconnection(DisconnectionFrame::default()).await
+    }
+
+    async fn send_disconnection(
+        &mut self,
+        disconnection: DisconnectionFrame,
+    ) -> Result<(), anyhow::Error> {
         let frame = location_nearby_connections::OfflineFrame {
             version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
             v1: Some(location_nearby_connections::V1Frame {
                 r#type: Some(
                     location_nearby_connections::v1_frame::FrameType::Disconnection.into(),
                 ),
-                disconnection: Some(location_nearby_connections::DisconnectionFrame {
-                    ..Default::default()
-                }),
+                disconnection: Some(disconnection),
                 ..Default::default()
             }),
         };
@@ -999,15 +1448,35 @@ impl InboundRequest {
     }
 
     async fn accept_transfer(&mut self) -> Result<(), anyhow::Error> {
+        // Space may have been used while waiting for the user's consent
+        if !self.has_enough_space() {
+            return self.reject_not_enough_space().await;
+        }
+
         let ids: Vec<i64> = self.state.transferred_files.keys().cloned().collect();
 
+        let mut resume_offsets = Vec::new();
         for id in ids {
             let mfi = self.state.transferred_files.get_mut(&id).unwrap();
 
-            let file = File::create(&mfi.file_url)?;
+            create_parent_dirs(&mfi.file_url)?;
+            let file = if mfi.bytes_transferred > 0 {
+                // Drop anything past what we know was correctly received
+                let part_url = get_part_path(&mfi.file_url, id);
+                let file = OpenOptions::new().write(true).open(&part_url)?;
+                file.set_len(mfi.bytes_transferred as u64)?;
+                resume_offsets.push(sharing_nearby::PayloadOffset {
+                    payload_id: Some(id),
+                    offset: Some(mfi.bytes_transferred),
+                });
+                file
+            } else {
+                self.resume.create_part(&mfi.file_url, id)?
+            };
             info!("Created file: {:?}", &file);
             mfi.file = Some(file);
         }
+        let resumed_bytes: i64 = resume_offsets.iter().map(|r| r.offset()).sum();
 
         let frame = sharing_nearby::Frame {
             version: Some(sharing_nearby::frame::Version::V1.into()),
@@ -1015,6 +1484,8 @@ impl InboundRequest {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
                 connection_response: Some(sharing_nearby::ConnectionResponseFrame {
                     status: Some(sharing_nearby::connection_response_frame::Status::Accept.into()),
+                    rquickshare_extension: (!resume_offsets.is_empty())
+                        .then_some(sharing_nearby::ConnectionResponseExtension { resume_offsets }),
                 }),
                 ..Default::default()
             }),
@@ -1025,14 +1496,121 @@ impl InboundRequest {
         self.update_state(
             |e| {
                 e.state = State::ReceivingFiles;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.ack_bytes += resumed_bytes as u64;
+                }
             },
             true,
         )
         .await;
 
+        // Now that the sender is ready, tell it about the skipped payloads
+        if !self.dropped_payloads.is_empty() {
+            let skipped: Vec<_> = self
+                .dropped_payloads
+                .iter()
+                .map(|(id, t)| (*id, *t))
+                .collect();
+            for (payload_id, payload_type) in skipped {
+                self.send_control_message(
+                    payload_id,
+                    payload_type,
+                    control_message::EventType::PayloadCanceled,
+                    0,
+                )
+                .await?;
+            }
+            self.check_transfer_finished().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn skip_payload(&mut self, payload_id: i64) -> Result<(), anyhow::Error> {
+        if !self.state.transferred_files.contains_key(&payload_id)
+            && !self.state.text_payloads.contains_key(&payload_id)
+            && !self.state.streams.contains_key(&payload_id)
+        {
+            warn!("Cannot skip unknown payload {payload_id}");
+            return Ok(());
+        }
+
+        match self.state.state {
+            // The sender will be told once the transfer is accepted
+            State::WaitingForUserConsent => {
+                info!("Skipping payload {payload_id}");
+                self.drop_payload(payload_id).await;
+            }
+            State::ReceivingFiles => {
+                info!("Skipping payload {payload_id}");
+                let offset = self
+                    .state
+                    .transferred_files
+                    .get(&payload_id)
+                    .map(|mfi| mfi.bytes_transferred)
+                    .unwrap_or_default();
+                self.send_control_message(
+                    payload_id,
+                    self.payload_type(payload_id),
+                    control_message::EventType::PayloadCanceled,
+                    offset,
+                )
+                .await?;
+                self.drop_payload(payload_id).await;
+                self.check_transfer_finished().await?;
+            }
+            _ => {
+                warn!(
+                    "Cannot skip payload {payload_id} in state {:?}",
+                    self.state.state
+                );
+            }
+        }
+
         Ok(())
     }
 
+    fn has_enough_space(&self) -> bool {
+        let needed: u64 = self
+            .state
+            .transferred_files
+            .values()
+            .map(|mfi| (mfi.total_size - mfi.bytes_transferred).max(0) as u64)
+            .fold(0, u64::saturating_add);
+        if needed == 0 {
+            return true;
+        }
+
+        match available_space(&self.download_dir()) {
+            Ok(available) => {
+                debug!("Space needed: {needed}, available: {available}");
+                available >= needed
+            }
+            Err(e) => {
+                // Don't refuse a transfer only because we couldn't tell
+                warn!("Couldn't get the available space: {}", e);
+                true
+            }
+        }
+    }
+
+    async fn reject_not_enough_space(&mut self) -> Result<(), anyhow::Error> {
+        warn!("Not enough space to receive the transfer");
+        self.update_state(
+            |e| {
+                e.state = State::NotEnoughSpace;
+            },
+            true,
+        )
+        .await;
+
+        self.reject_transfer(Some(
+            sharing_nearby::connection_response_frame::Status::NotEnoughSpace,
+        ))
+        .await?;
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
     async fn reject_transfer(
         &mut self,
         reason: Option<sharing_nearby::connection_response_frame::Status>,
@@ -1049,6 +1627,7 @@ impl InboundRequest {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::Response.into()),
                 connection_response: Some(sharing_nearby::ConnectionResponseFrame {
                     status: Some(sreason.into()),
+                    ..Default::default()
                 }),
                 ..Default::default()
             }),
@@ -1059,67 +1638,35 @@ impl InboundRequest {
         Ok(())
     }
 
-    async fn finalize_key_exchange(
-        &mut self,
-        raw_peer_key: GenericPublicKey,
-    ) -> Result<(), anyhow::Error> {
-        let peer_p256_key = raw_peer_key
-            .ec_p256_public_key
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
-
-        let mut bytes = vec![0x04];
-        // Ensure no more than 32 bytes for the keys
-        if peer_p256_key.x.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.x[peer_p256_key.x.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.x);
-        }
-        if peer_p256_key.y.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.y[peer_p256_key.y.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.y);
-        }
-
-        let encoded_point = EncodedPoint::from_bytes(bytes)?;
-        let peer_key = PublicKey::from_encoded_point(&encoded_point).unwrap();
-        let priv_key = self.state.private_key.as_ref().unwrap();
-
-        let dhs = diffie_hellman(priv_key.to_nonzero_scalar(), peer_key.as_affine());
-        let derived_secret = Sha256::digest(dhs.raw_secret_bytes());
-
-        let mut ukey_info: Vec<u8> = vec![];
-        ukey_info.extend_from_slice(self.state.client_init_msg_data.as_ref().unwrap());
-        ukey_info.extend_from_slice(self.state.server_init_data.as_ref().unwrap());
-
-        let auth_label = "UKEY2 v1 auth".as_bytes();
-        let next_label = "UKEY2 v1 next".as_bytes();
-
-        let auth_string = hkdf_extract_expand(auth_label, &derived_secret, &ukey_info, 32)?;
-        let next_secret = hkdf_extract_expand(next_label, &derived_secret, &ukey_info, 32)?;
-
-        let salt_hex = "82AA55A0D397F88346CA1CEE8D3909B95F13FA7DEB1D4AB38376B8256DA85510";
-        let salt =
-            hex::decode(salt_hex).map_err(|e| anyhow!("Failed to decode salt_hex: {}", e))?;
-
-        let d2d_client = hkdf_extract_expand(&salt, &next_secret, "client".as_bytes(), 32)?;
-        let d2d_server = hkdf_extract_expand(&salt, &next_secret, "server".as_bytes(), 32)?;
-
-        let key_salt_hex = "BF9D2A53C63616D75DB0A7165B91C1EF73E537F2427405FA23610A4BE657642E";
-        let key_salt = hex::decode(key_salt_hex)
-            .map_err(|e| anyhow!("Failed to decode key_salt_hex: {}", e))?;
-
-        let client_key = hkdf_extract_expand(&key_salt, &d2d_client, "ENC:2".as_bytes(), 32)?;
-        let client_hmac_key = hkdf_extract_expand(&key_salt, &d2d_client, "SIG:1".as_bytes(), 32)?;
-        let server_key = hkdf_extract_expand(&key_salt, &d2d_server, "ENC:2".as_bytes(), 32)?;
-        let server_hmac_key = hkdf_extract_expand(&key_salt, &d2d_server, "SIG:1".as_bytes(), 32)?;
+    async fn finalize_key_exchange(&mut self, raw_peer_key: &[u8]) -> Result<(), anyhow::Error> {
+        let cipher = self
+            .state
+            .cipher_commitment
+            .as_ref()
+            .ok_or_else(|| anyhow!("No handshake cipher negotiated"))?
+            .handshake_cipher();
+        let derived_secret = ukey2_derived_secret(
+            cipher,
+            self.state.private_key.as_ref(),
+            self.state.x25519_private_key.as_ref(),
+            raw_peer_key,
+        )?;
+
+        let channel = SecureChannel::new(
+            SecureChannelRole::Server,
+            &derived_secret,
+            self.state
+                .client_init_msg_data
+                .as_deref()
+                .unwrap_or_default(),
+            self.state.server_init_data.as_deref().unwrap_or_default(),
+        )?;
+        let pin_code = channel.pin_code();
 
         self.update_state(
             |e| {
-                e.decrypt_key = Some(client_key);
-                e.recv_hmac_key = Some(client_hmac_key);
-                e.encrypt_key = Some(server_key);
-                e.send_hmac_key = Some(server_hmac_key);
-                e.pin_code = Some(to_four_digit_string(&auth_string));
+                e.secure_channel = Some(channel);
+                e.pin_code = Some(pin_code);
                 e.encryption_done = true;
             },
             false,
@@ -1215,48 +1762,14 @@ impl InboundRequest {
     }
 
     async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
-        let d2d_msg = DeviceToDeviceMessage {
-            sequence_number: Some(self.get_server_seq_inc().await),
-            message: Some(frame.encode_to_vec()),
-        };
-
-        let key = self.state.encrypt_key.as_ref().unwrap();
-        let msg_data = d2d_msg.encode_to_vec();
-        let iv = gen_random(16);
-
-        let mut cipher = Cipher::new_256(&key[..AES_256_KEY_LEN].try_into().unwrap());
-        cipher.set_auto_padding(true);
-        let encrypted = cipher.cbc_encrypt(&iv, &msg_data);
-
-        let hb = HeaderAndBody {
-            body: encrypted,
-            header: Header {
-                encryption_scheme: EncScheme::Aes256Cbc.into(),
-                signature_scheme: SigScheme::HmacSha256.into(),
-                iv: Some(iv),
-                public_metadata: Some(
-                    GcmMetadata {
-                        r#type: Type::DeviceToDeviceMessage.into(),
-                        version: Some(1),
-                    }
-                    .encode_to_vec(),
-                ),
-                ..Default::default()
-            },
-        };
-
-        let mut hmac = HmacSha256::new_from_slice(self.state.send_hmac_key.as_ref().unwrap())?;
-        hmac.update(&hb.encode_to_vec());
-        let result = hmac.finalize();
-
-        let smsg = SecureMessage {
-            header_and_body: hb.encode_to_vec(),
-            signature: result.into_bytes().to_vec(),
-        };
-
-        self.send_frame(smsg.encode_to_vec()).await?;
-
-        Ok(())
+        let data = self
+            .state
+            .secure_channel
+            .as_mut()
+            .ok_or_else(|| anyhow!("Cannot encrypt before the end of the handshake"))?
+            .encrypt(&frame.encode_to_vec())?;
+
+        self.send_frame(data).await
     }
 
     async fn send_keepalive(&mut self, ack: bool) -> Result<(), anyhow::Error> {
@@ -1291,36 +1804,17 @@ impl InboundRequest {
         prefixed_length.extend_from_slice(&length_bytes);
         prefixed_length.extend_from_slice(&data);
 
-        self.socket.write_all(&prefixed_length).await?;
-        self.socket.flush().await?;
+        // A sleeping remote would otherwise block us here forever
+        tokio::time::timeout(self.keep_alive.timeout, async {
+            self.socket.write_all(&prefixed_length).await?;
+            self.socket.flush().await
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out writing to the remote device"))??;
 
         Ok(())
     }
 
-    async fn get_server_seq_inc(&mut self) -> i32 {
-        self.update_state(
-            |e| {
-                e.server_seq += 1;
-            },
-            false,
-        )
-        .await;
-
-        self.state.server_seq
-    }
-
-    async fn get_client_seq_inc(&mut self) -> i32 {
-        self.update_state(
-            |e| {
-                e.client_seq += 1;
-            },
-            false,
-        )
-        .await;
-
-        self.state.client_seq
-    }
-
     async fn update_state<F>(&mut self, f: F, inform: bool)
     where
         F: FnOnce(&mut InnerState),
@@ -1346,3 +1840,90 @@ impl InboundRequest {
         tokio::time::sleep(SANITY_DURATION).await;
     }
 }
+
+// Whatever the reason the request ends (finished, cancelled, disconnected
+// or the service being stopped), never leave half-written files behind.
+impl<S> Drop for InboundRequest<S> {
+    fn drop(&mut self) {
+        // Still receiving means the connection was lost (or errored)
+        let interrupted = matches!(
+            self.state.state,
+            State::ReceivingFiles | State::Disconnected
+        );
+
+        for (_, mfi) in self.state.transferred_files.drain() {
+            // The only exception: a file the sender could offer again, as a
+            // resume key was recorded for it, is kept a while to be resumed
+            // (and removed on expiry or RQS::stop).
+            match mfi.resume_key.clone() {
+                Some(key) if interrupted && mfi.bytes_transferred > 0 => {
+                    self.resume.save(key, mfi);
+                }
+                _ => self.resume.remove_part(&mfi),
+            }
+        }
+    }
+}
+
+// Path inside the download directory where a received file named `name`
+// will be written, prefixed with a counter if it already exists. The
+// parent_folder sent by the remote is recreated under the download dir.
+fn get_destination(mut dest: PathBuf, parent_folder: Option<&str>, name: &str) -> PathBuf {
+    // Both are controlled by the remote device, never trust them
+    let name = sanitize_filename(name);
+    if let Some(parent) = parent_folder {
+        dest.push(sanitize_relative_path(parent));
+    }
+    dest.push(&name);
+
+    unique_destination(dest)
+}
+
+// Prefix the file name with a counter until it doesn't collide with
+// an existing file.
+fn unique_destination(mut dest: PathBuf) -> PathBuf {
+    if !dest.exists() {
+        return dest;
+    }
+
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut counter = 1;
+    dest.pop();
+
+    loop {
+        dest.push(format!("{}_{}", counter, name));
+        if !dest.exists() {
+            break;
+        }
+        dest.pop();
+        counter += 1;
+    }
+
+    dest
+}
+
+// Move the fully received temporary file to its final destination.
+fn finalize_file(mfi: &mut InternalFileInfo, resume: &ResumeStore) -> Result<(), anyhow::Error> {
+    if let Some(file) = mfi.file.take() {
+        file.sync_all()?;
+    }
+
+    // Something may have been created there since the introduction
+    let dest = unique_destination(mfi.file_url.clone());
+    resume.finalize_part(mfi, &dest)?;
+    info!("Finalized file: {:?}", dest);
+    mfi.file_url = dest;
+
+    Ok(())
+}
+
+fn create_parent_dirs(dest: &Path) -> Result<(), anyhow::Error> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    Ok(())
+}
@@ -14,6 +14,10 @@ pub struct InternalFileInfo {
     pub bytes_transferred: i64,
     pub total_size: i64,
     pub file: Option<File>,
+    // Folder relative to the shared directory, if sent as part of one
+    pub parent_folder: Option<String>,
+    // Identifies the file across connections to resume it, if possible
+    pub resume_key: Option<String>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize, TS)]
@@ -30,6 +34,34 @@ pub struct TransferMetadata {
     pub text_description: Option<String>,
     pub text_payload: Option<String>,
 
+    // Every file/text/wifi of the transfer, tracked by payload id
+    pub items: Vec<TransferItem>,
+
     pub total_bytes: u64,
     pub ack_bytes: u64,
+
+    // Only set once the transfer is State::Cancelled
+    pub cancelled_by: Option<CancelOrigin>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum CancelOrigin {
+    // The user of this device
+    Local,
+    // The remote device (or its user)
+    Remote,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct TransferItem {
+    pub payload_id: i64,
+    // File name, text title or wifi ssid
+    pub name: String,
+    pub size: i64,
+
+    // Only present for text/url/wifi, text_payload is set once received
+    pub text_type: Option<TextPayloadType>,
+    pub text_payload: Option<String>,
 }
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// Same defaults as Nearby Connections
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(5000);
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_millis(30000);
+
+/// Schedule of the keep-alive frames we send, and detection of a
+/// remote device which stopped sending anything.
+#[derive(Debug)]
+pub(crate) struct KeepAlive {
+    pub interval: Duration,
+    pub timeout: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        }
+    }
+}
+
+impl KeepAlive {
+    /// Use the values advertised by the remote device, if they make sense.
+    pub fn configure(&mut self, interval_millis: i32, timeout_millis: i32) {
+        if interval_millis <= 0 || timeout_millis <= interval_millis {
+            return;
+        }
+
+        debug!("Keep-alive: every {interval_millis}ms, timeout after {timeout_millis}ms");
+        self.interval = Duration::from_millis(interval_millis as u64);
+        self.timeout = Duration::from_millis(timeout_millis as u64);
+    }
+
+    pub fn next_send(&self) -> Instant {
+        self.last_sent + self.interval
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.last_received + self.timeout
+    }
+
+    pub fn sent(&mut self) {
+        self.last_sent = Instant::now();
+    }
+
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+}
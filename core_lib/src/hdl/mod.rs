@@ -2,11 +2,13 @@ use std::collections::HashMap;
 
 use p256::{PublicKey, SecretKey};
 use serde::{Deserialize, Serialize};
+use tokio::io::DuplexStream;
 use ts_rs::TS;
 
 use self::info::{InternalFileInfo, TransferMetadata};
 use crate::securegcm::ukey2_client_init::CipherCommitment;
-use crate::utils::RemoteDeviceInfo;
+use crate::securegcm::Ukey2HandshakeCipher;
+use crate::utils::{RemoteDeviceInfo, X25519Secret};
 
 #[cfg(feature = "experimental")]
 mod ble;
@@ -16,15 +18,36 @@ pub use ble::*;
 mod blea;
 #[cfg(all(feature = "experimental", target_os = "linux"))]
 pub use blea::*;
+mod bwu;
+pub(crate) use bwu::*;
 mod inbound;
 pub use inbound::*;
 pub(crate) mod info;
+mod keepalive;
+pub(crate) use keepalive::*;
 mod mdns_discovery;
 pub use mdns_discovery::*;
 mod mdns;
 pub use mdns::*;
 mod outbound;
 pub use outbound::*;
+mod parse;
+pub use parse::*;
+mod resume;
+pub use resume::ResumeStore;
+pub(crate) use resume::*;
+mod secure_channel;
+pub use secure_channel::*;
+mod stream;
+pub use stream::*;
+mod transport;
+pub use transport::*;
+
+// Handshake ciphers we support, the strongest first
+pub(crate) const UKEY2_CIPHERS: [Ukey2HandshakeCipher; 2] = [
+    Ukey2HandshakeCipher::Curve25519Sha512,
+    Ukey2HandshakeCipher::P256Sha512,
+];
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Default, Serialize, Deserialize, TS, PartialEq)]
@@ -47,6 +70,12 @@ pub enum State {
     SendingFiles,
     Disconnected,
     Rejected,
+    // Rejected because the download directory doesn't have enough free space
+    NotEnoughSpace,
+    // Nobody accepted (or rejected) the transfer in time
+    TimedOut,
+    // A received message was tampered with, replayed or reordered
+    SecurityError,
     Cancelled,
     Finished,
 }
@@ -54,8 +83,6 @@ pub enum State {
 #[derive(Debug, Default)]
 pub struct InnerState {
     pub id: String,
-    pub server_seq: i32,
-    pub client_seq: i32,
     pub encryption_done: bool,
 
     // Subject to be used-facing for progress, ...
@@ -69,20 +96,19 @@ pub struct InnerState {
     pub cipher_commitment: Option<CipherCommitment>,
     pub private_key: Option<SecretKey>,
     pub public_key: Option<PublicKey>,
+    pub x25519_private_key: Option<X25519Secret>,
     pub server_init_data: Option<Vec<u8>>,
     pub client_init_msg_data: Option<Vec<u8>>,
-    pub ukey_client_finish_msg_data: Option<Vec<u8>>,
-    pub decrypt_key: Option<Vec<u8>>,
-    pub recv_hmac_key: Option<Vec<u8>>,
-    pub encrypt_key: Option<Vec<u8>>,
-    pub send_hmac_key: Option<Vec<u8>>,
+    // ClientFinished message for each cipher we committed to, as the client
+    pub ukey_client_finish_msgs: Vec<(CipherCommitment, Vec<u8>)>,
+    // Set once the UKEY2 handshake is done
+    pub secure_channel: Option<SecureChannel>,
 
     // Used to handle/track ingress transfer
-    pub text_payload: Option<TextPayloadInfo>,
-    // pub text_payload_id: i64,
-    // pub text_is_url: bool,
-    // pub wifi_ssid: Option<String>,
+    pub text_payloads: HashMap<i64, TextPayloadInfo>,
     pub payload_buffers: HashMap<i64, Vec<u8>>,
+    // Writing side of the streams forwarded to the library consumer
+    pub streams: HashMap<i64, DuplexStream>,
 }
 
 #[derive(Debug, Clone)]
@@ -92,7 +118,7 @@ pub enum TextPayloadInfo {
     Wifi((i64, String)),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
 pub enum TextPayloadType {
     Url,
     Text,
@@ -107,4 +133,12 @@ impl TextPayloadInfo {
             | TextPayloadInfo::Wifi((value, _)) => value.to_owned(),
         }
     }
+
+    fn get_type(&self) -> TextPayloadType {
+        match self {
+            TextPayloadInfo::Url(_) => TextPayloadType::Url,
+            TextPayloadInfo::Text(_) => TextPayloadType::Text,
+            TextPayloadInfo::Wifi(_) => TextPayloadType::Wifi,
+        }
+    }
 }
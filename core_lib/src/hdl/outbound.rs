@@ -1,115 +1,233 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use anyhow::anyhow;
-use bytes::Bytes;
-use hmac::{Hmac, Mac};
-use libaes::{Cipher, AES_256_KEY_LEN};
-use p256::ecdh::diffie_hellman;
-use p256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
-use p256::{EncodedPoint, PublicKey};
 use prost::Message;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256, Sha512};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::Instant;
 use ts_rs::TS;
 
-use super::info::{InternalFileInfo, TransferMetadata};
-use super::{InnerState, State};
+use super::info::{CancelOrigin, InternalFileInfo, TransferItem, TransferMetadata};
+use super::{
+    append_bytes_chunk, bwu_frame, connect_wifi_lan, wifi_lan_address, BandwidthUpgrade,
+    InnerState, KeepAlive, SecureChannel, SecureChannelError, SecureChannelRole, State,
+    TextPayloadInfo, TextPayloadType, Transport, UKEY2_CIPHERS,
+};
 use crate::channel::{ChannelAction, ChannelDirection, ChannelMessage};
 use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::upgrade_path_info::Medium;
+use crate::location_nearby_connections::bandwidth_upgrade_negotiation_frame::{
+    EventType, UpgradePathInfo,
+};
 use crate::location_nearby_connections::connection_response_frame::ResponseStatus;
 use crate::location_nearby_connections::payload_transfer_frame::{
-    payload_header, PacketType, PayloadChunk, PayloadHeader,
+    control_message, payload_header, ControlMessage, PacketType, PayloadChunk, PayloadHeader,
+};
+use crate::location_nearby_connections::{
+    BandwidthUpgradeNegotiationFrame, DisconnectionFrame, KeepAliveFrame, OfflineFrame,
+    PayloadTransferFrame,
 };
-use crate::location_nearby_connections::{KeepAliveFrame, OfflineFrame, PayloadTransferFrame};
 use crate::securegcm::ukey2_alert::AlertType;
 use crate::securegcm::ukey2_client_init::CipherCommitment;
 use crate::securegcm::{
-    ukey2_message, DeviceToDeviceMessage, GcmMetadata, Type, Ukey2Alert, Ukey2ClientFinished,
-    Ukey2ClientInit, Ukey2HandshakeCipher, Ukey2Message, Ukey2ServerInit,
-};
-use crate::securemessage::{
-    EcP256PublicKey, EncScheme, GenericPublicKey, Header, HeaderAndBody, PublicKeyType,
-    SecureMessage, SigScheme,
+    ukey2_message, Ukey2Alert, Ukey2ClientFinished, Ukey2ClientInit, Ukey2HandshakeCipher,
+    Ukey2Message, Ukey2ServerInit,
 };
 use crate::sharing_nearby::{
-    file_metadata, paired_key_result_frame, FileMetadata, IntroductionFrame,
+    file_metadata, paired_key_result_frame, text_metadata, wifi_credentials_metadata, FileMetadata,
+    IntroductionExtension, IntroductionFrame, TextMetadata, WifiCredentials,
+    WifiCredentialsMetadata,
 };
 use crate::utils::{
-    encode_point, gen_ecdsa_keypair, gen_random, hkdf_extract_expand, stream_read_exact,
-    to_four_digit_string, DeviceType, RemoteDeviceInfo,
+    encode_p256_public_key, gen_ecdsa_keypair, gen_random, gen_x25519_keypair,
+    get_outbound_consent_timeout, stream_read_exact, ukey2_derived_secret, DeviceType,
+    RemoteDeviceInfo,
 };
 use crate::{location_nearby_connections, sharing_nearby};
 
-type HmacSha256 = Hmac<Sha256>;
-
 const SANE_FRAME_LENGTH: i32 = 5 * 1024 * 1024;
 const SANITY_DURATION: Duration = Duration::from_micros(10);
+const TEXT_TITLE_MAX_CHARS: usize = 64;
 
-#[derive(Debug, Deserialize, Serialize, TS)]
+#[derive(Debug, Clone, Deserialize, Serialize, TS)]
 #[ts(export)]
 pub enum OutboundPayload {
     Files(Vec<String>),
+    Text(String),
+    Url(String),
+    WifiCredentials {
+        ssid: String,
+        password: String,
+        security_type: WifiSecurityType,
+        hidden: bool,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum WifiSecurityType {
+    Open,
+    WpaPsk,
+    Wep,
+}
+
+impl From<&WifiSecurityType> for wifi_credentials_metadata::SecurityType {
+    fn from(value: &WifiSecurityType) -> Self {
+        match value {
+            WifiSecurityType::Open => wifi_credentials_metadata::SecurityType::Open,
+            WifiSecurityType::WpaPsk => wifi_credentials_metadata::SecurityType::WpaPsk,
+            WifiSecurityType::Wep => wifi_credentials_metadata::SecurityType::Wep,
+        }
+    }
+}
+
+impl OutboundPayload {
+    // Content of the Bytes payload sent after the consent,
+    // None for payloads which are sent as files
+    fn bytes_payload(&self) -> Option<Vec<u8>> {
+        match self {
+            OutboundPayload::Files(_) => None,
+            OutboundPayload::Text(text) | OutboundPayload::Url(text) => {
+                Some(text.as_bytes().to_vec())
+            }
+            OutboundPayload::WifiCredentials {
+                password, hidden, ..
+            } => Some(
+                WifiCredentials {
+                    password: Some(password.to_owned()),
+                    hidden_ssid: Some(*hidden),
+                }
+                .encode_to_vec(),
+            ),
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct OutboundRequest {
+pub struct OutboundRequest<S = TcpStream> {
     endpoint_id: [u8; 4],
-    socket: TcpStream,
+    socket: S,
     pub state: InnerState,
     sender: Sender<ChannelMessage>,
     receiver: Receiver<ChannelMessage>,
     payload: OutboundPayload,
+    // Files left to send, in order
+    send_queue: VecDeque<i64>,
+    // Offsets acknowledged by the receiver, if it sends PAYLOAD_RECEIVED_ACK
+    acked_offsets: HashMap<i64, i64>,
+    peer_acks: bool,
+    // Size of the files sent in full, which the acks are checked against
+    sent_sizes: HashMap<i64, i64>,
+    // The frame length is read in a cancel-safe way, as we're also sending
+    length_buf: [u8; 4],
+    length_read: usize,
+    // Set while waiting for the remote user to accept the transfer
+    consent_deadline: Option<Instant>,
+    consent_timeout: Arc<RwLock<Option<Duration>>>,
+    keep_alive: KeepAlive,
+    bwu: BandwidthUpgrade<S>,
+    // Set once everything was sent, while waiting for the receiver's ack
+    disconnect_deadline: Option<Instant>,
+    // Only set when the same payload is sent to several devices
+    group_id: Option<String>,
+    prepared_files: Option<Arc<Vec<PreparedFile>>>,
 }
 
-impl OutboundRequest {
+impl<S: Transport> OutboundRequest<S> {
     pub fn new(
         endpoint_id: [u8; 4],
-        socket: TcpStream,
+        socket: S,
         id: String,
         sender: Sender<ChannelMessage>,
         payload: OutboundPayload,
         rdi: RemoteDeviceInfo,
+        consent_timeout: Arc<RwLock<Option<Duration>>>,
     ) -> Self {
         let receiver = sender.subscribe();
-        let OutboundPayload::Files(files) = &payload;
+        let transfer_metadata = match &payload {
+            OutboundPayload::Files(files) => TransferMetadata {
+                id: String::from(""),
+                source: Some(rdi),
+                files: Some(files.to_owned()),
+                ..Default::default()
+            },
+            OutboundPayload::Text(text) => TransferMetadata {
+                id: String::from(""),
+                source: Some(rdi),
+                text_type: Some(TextPayloadType::Text),
+                text_payload: Some(text.to_owned()),
+                ..Default::default()
+            },
+            OutboundPayload::Url(url) => TransferMetadata {
+                id: String::from(""),
+                source: Some(rdi),
+                text_type: Some(TextPayloadType::Url),
+                text_payload: Some(url.to_owned()),
+                ..Default::default()
+            },
+            OutboundPayload::WifiCredentials { ssid, .. } => TransferMetadata {
+                id: String::from(""),
+                source: Some(rdi),
+                text_type: Some(TextPayloadType::Wifi),
+                text_description: Some(ssid.to_owned()),
+                ..Default::default()
+            },
+        };
 
         Self {
             endpoint_id,
             socket,
             state: InnerState {
                 id,
-                server_seq: 0,
-                client_seq: 0,
                 state: State::Initial,
                 encryption_done: true,
-                transfer_metadata: Some(TransferMetadata {
-                    id: String::from(""),
-                    source: Some(rdi),
-                    files: Some(files.to_owned()),
-                    ..Default::default()
-                }),
+                transfer_metadata: Some(transfer_metadata),
                 ..Default::default()
             },
             sender,
             receiver,
             payload,
+            send_queue: VecDeque::new(),
+            acked_offsets: HashMap::new(),
+            peer_acks: false,
+            sent_sizes: HashMap::new(),
+            length_buf: [0u8; 4],
+            length_read: 0,
+            consent_deadline: None,
+            consent_timeout,
+            keep_alive: KeepAlive::default(),
+            bwu: BandwidthUpgrade::default(),
+            disconnect_deadline: None,
+            group_id: None,
+            prepared_files: None,
         }
     }
 
-    pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
-        // Buffer for the 4-byte length
-        let mut length_buf = [0u8; 4];
+    // Part of a fan-out send: the states are reported under group_id, and
+    // the files were already prepared (if the payload is made of files).
+    pub fn with_group(
+        mut self,
+        group_id: String,
+        prepared_files: Option<Arc<Vec<PreparedFile>>>,
+    ) -> Self {
+        self.group_id = Some(group_id);
+        self.prepared_files = prepared_files;
+        self
+    }
 
+    pub async fn handle(&mut self) -> Result<(), anyhow::Error> {
         tokio::select! {
+            biased;
+
             i = self.receiver.recv() => {
                 match i {
                     Ok(channel_msg) => {
@@ -124,15 +242,12 @@ impl OutboundRequest {
                         debug!("outbound: got: {:?}", channel_msg);
                         match channel_msg.action {
                             Some(ChannelAction::CancelTransfer) => {
-                                self.update_state(
-                                    |e| {
-                                        e.state = State::Cancelled;
-                                    },
-                                    true,
-                                ).await;
-                                self.disconnection().await?;
+                                self.cancel(CancelOrigin::Local).await?;
                                 return Err(anyhow!(crate::errors::AppError::NotAnError));
                             },
+                            Some(ChannelAction::CancelPayload(payload_id)) => {
+                                self.cancel_payload(payload_id).await?;
+                            },
                             None => {
                                 trace!("inbound: nothing to do")
                             },
@@ -144,10 +259,76 @@ impl OutboundRequest {
                     }
                 }
             },
-            h = stream_read_exact(&mut self.socket, &mut length_buf) => {
-                h?;
+            _ = tokio::time::sleep_until(self.consent_deadline.unwrap_or_else(Instant::now)),
+                if self.consent_deadline.is_some()
+                    && self.state.state == State::SentIntroduction => {
+                info!("Remote consent timed out, giving up");
+                self.update_state(
+                    |e| {
+                        e.state = State::TimedOut;
+                    },
+                    true,
+                ).await;
+                self.disconnection().await?;
+                return Err(anyhow!(crate::errors::AppError::NotAnError));
+            }
+            _ = tokio::time::sleep_until(self.disconnect_deadline.unwrap_or_else(Instant::now)),
+                if self.disconnect_deadline.is_some() => {
+                warn!("The receiver never confirmed it got everything");
+                self.update_state(
+                    |e| {
+                        e.state = State::Disconnected;
+                    },
+                    true,
+                ).await;
+                let _ = self.disconnection().await;
+                return Err(anyhow!(crate::errors::AppError::NotAnError));
+            },
+            _ = tokio::time::sleep_until(self.keep_alive.deadline()) => {
+                if self.state.state == State::Finished {
+                    return Err(anyhow!(crate::errors::AppError::NotAnError));
+                }
 
-                self._handle(length_buf).await?
+                warn!("Nothing received from the remote device for {:?}, disconnecting", self.keep_alive.timeout);
+                // The frontend only knows about the transfer once it has metadata
+                let inform = self.state.transfer_metadata.is_some();
+                self.update_state(
+                    |e| {
+                        e.state = State::Disconnected;
+                    },
+                    inform,
+                ).await;
+                return Err(anyhow!(crate::errors::AppError::NotAnError));
+            },
+            _ = tokio::time::sleep_until(self.keep_alive.next_send()), if self.state.secure_channel.is_some() => {
+                trace!("Sending keepalive");
+                self.keep_alive.sent();
+                self.send_keepalive(false).await?;
+            },
+            n = self.bwu.prior_socket.as_mut().unwrap_or(&mut self.socket)
+                .read(&mut self.length_buf[self.length_read..]) => {
+                let n = n?;
+                if n == 0 {
+                    // Closing the connection is the only answer from some receivers
+                    if self.disconnect_deadline.is_some() {
+                        debug!("Remote device closed the connection");
+                        return self.closed_after_sending().await;
+                    }
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+
+                self.keep_alive.received();
+                self.length_read += n;
+                if self.length_read == self.length_buf.len() {
+                    self.length_read = 0;
+                    self._handle(self.length_buf).await?
+                }
+            }
+            // Only when nothing else is ready, so that incoming frames
+            // (acks, cancellations) are handled in between chunks.
+            _ = std::future::ready(()),
+                if self.state.state == State::SendingFiles && self.disconnect_deadline.is_none() => {
+                self.send_next_chunk().await?;
             }
         }
 
@@ -164,7 +345,9 @@ impl OutboundRequest {
 
         // Allocate buffer for the actual message and read it
         let mut frame_data = vec![0u8; msg_length];
-        stream_read_exact(&mut self.socket, &mut frame_data).await?;
+        // Until the upgrade is done, frames keep coming from the prior socket
+        let reader = self.bwu.prior_socket.as_mut().unwrap_or(&mut self.socket);
+        stream_read_exact(reader, &mut frame_data).await?;
 
         let current_state = &self.state;
         // Now determine what will be the request type based on current state
@@ -209,8 +392,7 @@ impl OutboundRequest {
             }
             _ => {
                 debug!("Handling SecureMessage frame");
-                let smsg = SecureMessage::decode(&*frame_data)?;
-                self.decrypt_and_process_secure_message(&smsg).await?;
+                self.decrypt_and_process_secure_message(&frame_data).await?;
             }
         }
 
@@ -226,6 +408,8 @@ impl OutboundRequest {
                 ),
                 connection_request: Some(location_nearby_connections::ConnectionRequestFrame {
                     endpoint_id: Some(String::from_utf8_lossy(&self.endpoint_id).to_string()),
+                    keep_alive_interval_millis: Some(self.keep_alive.interval.as_millis() as i32),
+                    keep_alive_timeout_millis: Some(self.keep_alive.timeout.as_millis() as i32),
                     endpoint_name: Some(sys_metrics::host::get_hostname()?.into()),
                     endpoint_info: Some(
                         RemoteDeviceInfo {
@@ -234,7 +418,11 @@ impl OutboundRequest {
                         }
                         .serialize(),
                     ),
-                    mediums: vec![Medium::WifiLan.into()],
+                    mediums: self
+                        .socket
+                        .local_addr()
+                        .map(|_| vec![Medium::WifiLan.into()])
+                        .unwrap_or_default(),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -248,31 +436,36 @@ impl OutboundRequest {
 
     pub async fn send_ukey2_client_init(&mut self) -> Result<(), anyhow::Error> {
         let (secret_key, public_key) = gen_ecdsa_keypair();
+        let (x25519_secret_key, x25519_public_key) = gen_x25519_keypair();
+
+        // Commit to a ClientFinished for every cipher, the server picks one
+        let mut finish_msgs = Vec::with_capacity(UKEY2_CIPHERS.len());
+        for cipher in UKEY2_CIPHERS {
+            // Curve25519 keys are sent raw, P256 ones as a GenericPublicKey
+            let pkey = if cipher == Ukey2HandshakeCipher::Curve25519Sha512 {
+                x25519_public_key.as_bytes().to_vec()
+            } else {
+                encode_p256_public_key(&public_key)?
+            };
+
+            let finish_frame = Ukey2Message {
+                message_type: Some(ukey2_message::Type::ClientFinish.into()),
+                message_data: Some(
+                    Ukey2ClientFinished {
+                        public_key: Some(pkey),
+                    }
+                    .encode_to_vec(),
+                ),
+            }
+            .encode_to_vec();
 
-        let encoded_point = public_key.to_encoded_point(false);
-        let x = encoded_point.x().unwrap();
-        let y = encoded_point.y().unwrap();
-
-        let pkey = GenericPublicKey {
-            r#type: PublicKeyType::EcP256.into(),
-            ec_p256_public_key: Some(EcP256PublicKey {
-                x: encode_point(Bytes::from(x.to_vec()))?,
-                y: encode_point(Bytes::from(y.to_vec()))?,
-            }),
-            ..Default::default()
-        };
-
-        let finish_frame = Ukey2Message {
-            message_type: Some(ukey2_message::Type::ClientFinish.into()),
-            message_data: Some(
-                Ukey2ClientFinished {
-                    public_key: Some(pkey.encode_to_vec()),
-                }
-                .encode_to_vec(),
-            ),
-        };
+            let commitment = CipherCommitment {
+                handshake_cipher: Some(cipher.into()),
+                commitment: Some(Sha512::digest(&finish_frame).to_vec()),
+            };
+            finish_msgs.push((commitment, finish_frame));
+        }
 
-        let sha512 = Sha512::digest(finish_frame.encode_to_vec());
         let frame = Ukey2Message {
             message_type: Some(ukey2_message::Type::ClientInit.into()),
             message_data: Some(
@@ -280,10 +473,7 @@ impl OutboundRequest {
                     version: Some(1),
                     random: Some(gen_random(32)),
                     next_protocol: Some(String::from("AES_256_CBC-HMAC_SHA256")),
-                    cipher_commitments: vec![CipherCommitment {
-                        handshake_cipher: Some(Ukey2HandshakeCipher::P256Sha512.into()),
-                        commitment: Some(sha512.to_vec()),
-                    }],
+                    cipher_commitments: finish_msgs.iter().map(|(c, _)| c.clone()).collect(),
                 }
                 .encode_to_vec(),
             ),
@@ -296,8 +486,9 @@ impl OutboundRequest {
                 e.state = State::SentUkeyClientInit;
                 e.private_key = Some(secret_key);
                 e.public_key = Some(public_key);
+                e.x25519_private_key = Some(x25519_secret_key);
                 e.client_init_msg_data = Some(frame.encode_to_vec());
-                e.ukey_client_finish_msg_data = Some(finish_frame.encode_to_vec());
+                e.ukey_client_finish_msgs = finish_msgs;
             },
             false,
         )
@@ -332,21 +523,31 @@ impl OutboundRequest {
             return Err(anyhow!("UKey2: server_init.random.len != 32"));
         }
 
-        if server_init.handshake_cipher() != Ukey2HandshakeCipher::P256Sha512 {
+        // The server must pick one of the ciphers we committed to
+        let Some((commitment, finish_msg)) = self
+            .state
+            .ukey_client_finish_msgs
+            .iter()
+            .find(|(c, _)| c.handshake_cipher() == server_init.handshake_cipher())
+            .cloned()
+        else {
             self.send_ukey2_alert(AlertType::BadHandshakeCipher).await?;
-            return Err(anyhow!("UKey2: handshake_cipher != P256Sha512"));
-        }
-
-        let server_public_key = match GenericPublicKey::decode(server_init.public_key()) {
-            Ok(spk) => spk,
-            Err(e) => {
-                return Err(anyhow!("UKey2: GenericPublicKey::decode: {}", e));
-            }
+            return Err(anyhow!(
+                "UKey2: unexpected handshake_cipher: {:?}",
+                server_init.handshake_cipher()
+            ));
         };
+        debug!("Using handshake cipher {:?}", commitment.handshake_cipher());
+        self.update_state(
+            |e| {
+                e.cipher_commitment = Some(commitment);
+            },
+            false,
+        )
+        .await;
 
-        self.finalize_key_exchange(server_public_key).await?;
-        self.send_frame(self.state.ukey_client_finish_msg_data.clone().unwrap())
-            .await?;
+        self.finalize_key_exchange(server_init.public_key()).await?;
+        self.send_frame(finish_msg).await?;
 
         let frame = location_nearby_connections::OfflineFrame {
             version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
@@ -387,11 +588,12 @@ impl OutboundRequest {
             )));
         }
 
-        if v1_frame.connection_response.is_none() {
-            return Err(anyhow!(format!("Unexpected None connection_response",)));
-        }
+        let connection_response = v1_frame
+            .connection_response
+            .as_ref()
+            .ok_or_else(|| anyhow!(format!("Unexpected None connection_response",)))?;
 
-        if v1_frame.connection_response.as_ref().unwrap().response() != ResponseStatus::Accept {
+        if connection_response.response() != ResponseStatus::Accept {
             return Err(anyhow!(format!("Connection rejected by third party",)));
         }
 
@@ -415,40 +617,23 @@ impl OutboundRequest {
 
     async fn decrypt_and_process_secure_message(
         &mut self,
-        smsg: &SecureMessage,
+        data: &[u8],
     ) -> Result<(), anyhow::Error> {
-        let mut hmac = HmacSha256::new_from_slice(self.state.recv_hmac_key.as_ref().unwrap())?;
-        hmac.update(&smsg.header_and_body);
-        if !hmac
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .eq(smsg.signature.as_slice())
+        let message = match self
+            .state
+            .secure_channel
+            .as_mut()
+            .ok_or_else(|| anyhow!("Encrypted frame before the end of the handshake"))?
+            .decrypt(data)
         {
-            return Err(anyhow!("hmac!=signature"));
-        }
-
-        let header_and_body = HeaderAndBody::decode(&*smsg.header_and_body)?;
-
-        let msg_data = header_and_body.body;
-        let key = self.state.decrypt_key.as_ref().unwrap();
-
-        let mut cipher = Cipher::new_256(key[..AES_256_KEY_LEN].try_into()?);
-        cipher.set_auto_padding(true);
-        let decrypted = cipher.cbc_decrypt(header_and_body.header.iv(), &msg_data);
-
-        let d2d_msg = DeviceToDeviceMessage::decode(&*decrypted)?;
-
-        let seq = self.get_client_seq_inc().await;
-        if d2d_msg.sequence_number() != seq {
-            return Err(anyhow!(
-                "Error d2d_msg.sequence_number invalid ({} vs {})",
-                d2d_msg.sequence_number(),
-                seq
-            ));
-        }
+            Ok(m) => m,
+            Err(e) => match e.downcast_ref::<SecureChannelError>() {
+                Some(sce) => return self.security_error(sce).await,
+                None => return Err(e),
+            },
+        };
 
-        let offline = location_nearby_connections::OfflineFrame::decode(d2d_msg.message())?;
+        let offline = location_nearby_connections::OfflineFrame::decode(&*message)?;
         let v1_frame = offline
             .v1
             .as_ref()
@@ -465,6 +650,14 @@ impl OutboundRequest {
                     .payload_header
                     .as_ref()
                     .ok_or_else(|| anyhow!("Missing required fields"))?;
+                if payload_transfer.packet_type() == PacketType::Control {
+                    let control = payload_transfer
+                        .control_message
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("Missing required fields"))?;
+                    return self.process_control_message(header, control).await;
+                }
+
                 let chunk = payload_transfer
                     .payload_chunk
                     .as_ref()
@@ -475,33 +668,10 @@ impl OutboundRequest {
                         info!("Processing PayloadType::Bytes");
                         let payload_id = header.id();
 
-                        if header.total_size() > SANE_FRAME_LENGTH.into() {
+                        let buffer = self.state.payload_buffers.entry(payload_id).or_default();
+                        if let Err(e) = append_bytes_chunk(buffer, header.total_size(), chunk) {
                             self.state.payload_buffers.remove(&payload_id);
-                            return Err(anyhow!(
-                                "Payload too large: {} bytes",
-                                header.total_size()
-                            ));
-                        }
-
-                        self.state
-                            .payload_buffers
-                            .entry(payload_id)
-                            .or_insert_with(|| Vec::with_capacity(header.total_size() as usize));
-
-                        // Get the current length of the buffer, if it exists, without holding a mutable borrow.
-                        let buffer_len = self.state.payload_buffers.get(&payload_id).unwrap().len();
-                        if chunk.offset() != buffer_len as i64 {
-                            self.state.payload_buffers.remove(&payload_id);
-                            return Err(anyhow!(
-                                "Unexpected chunk offset: {}, expected: {}",
-                                chunk.offset(),
-                                buffer_len
-                            ));
-                        }
-
-                        let buffer = self.state.payload_buffers.get_mut(&payload_id).unwrap();
-                        if let Some(body) = &chunk.body {
-                            buffer.extend(body);
+                            return Err(e.into());
                         }
 
                         if (chunk.flags() & 1) == 1 {
@@ -525,9 +695,23 @@ impl OutboundRequest {
                     }
                 }
             }
+            location_nearby_connections::v1_frame::FrameType::BandwidthUpgradeNegotiation => {
+                let bwu = v1_frame
+                    .bandwidth_upgrade_negotiation
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Missing required fields"))?;
+                self.process_bandwidth_upgrade(bwu).await?;
+            }
             location_nearby_connections::v1_frame::FrameType::KeepAlive => {
-                trace!("Sending keepalive");
-                self.send_keepalive(true).await?;
+                // Acks aren't answered, or both sides would ack each other forever
+                if !v1_frame.keep_alive.as_ref().is_some_and(|k| k.ack()) {
+                    trace!("Sending keepalive");
+                    self.send_keepalive(true).await?;
+                }
+            }
+            location_nearby_connections::v1_frame::FrameType::Disconnection => {
+                let disconnection = v1_frame.disconnection.unwrap_or_default();
+                return self.process_remote_disconnection(&disconnection).await;
             }
             _ => {
                 error!("Unhandled offline frame encrypted: {:?}", offline);
@@ -547,15 +731,8 @@ impl OutboundRequest {
             .ok_or_else(|| anyhow!("Missing required fields"))?;
 
         if v1_frame.r#type() == sharing_nearby::v1_frame::FrameType::Cancel {
-            info!("Transfer canceled");
-            self.update_state(
-                |e| {
-                    e.state = State::Cancelled;
-                },
-                true,
-            )
-            .await;
-            self.disconnection().await?;
+            info!("Transfer canceled by the remote device");
+            self.cancel(CancelOrigin::Remote).await?;
             return Err(anyhow!(crate::errors::AppError::NotAnError));
         }
 
@@ -574,6 +751,8 @@ impl OutboundRequest {
             State::SentPairedKeyResult => {
                 debug!("Processing State::SentPairedKeyResult");
                 self.process_paired_key_result(v1_frame).await?;
+                self.consent_deadline =
+                    Some(Instant::now() + get_outbound_consent_timeout(&self.consent_timeout));
                 self.update_state(
                     |e| {
                         e.state = State::SentIntroduction;
@@ -631,83 +810,123 @@ impl OutboundRequest {
         }
 
         let mut file_metadata: Vec<FileMetadata> = vec![];
+        let mut text_metadata: Vec<TextMetadata> = vec![];
+        let mut wifi_credentials_metadata: Vec<WifiCredentialsMetadata> = vec![];
+        let mut text_payloads: HashMap<i64, TextPayloadInfo> = HashMap::new();
         let mut transferred_files: HashMap<i64, InternalFileInfo> = HashMap::new();
         let mut total_to_send = 0;
-        // TODO - Handle sending Text
         match &self.payload {
             OutboundPayload::Files(files) => {
-                for f in files {
-                    let path = Path::new(f);
-                    if !path.is_file() {
-                        warn!("Path is not a file: {}", f);
-                        continue;
-                    }
-
-                    let file = match File::open(f) {
+                // Already prepared when the same files go to several devices
+                let prepared = match &self.prepared_files {
+                    Some(p) => p.clone(),
+                    None => Arc::new(prepare_files(files)),
+                };
+
+                for pf in prepared.iter() {
+                    let file = match File::open(&pf.path) {
                         Ok(_f) => _f,
                         Err(e) => {
-                            error!("Failed to open file: {f}: {:?}", e);
-                            continue;
-                        }
-                    };
-                    let fmetadata = match file.metadata() {
-                        Ok(_fm) => _fm,
-                        Err(e) => {
-                            error!("Failed to get metadata for: {f}: {:?}", e);
+                            error!("Failed to open file: {}: {:?}", pf.path.display(), e);
                             continue;
                         }
                     };
 
-                    let ftype = mime_guess::from_path(path)
-                        .first_or_octet_stream()
-                        .to_string();
-
-                    let meta_type = if ftype.starts_with("image/") {
-                        file_metadata::Type::Image
-                    } else if ftype.starts_with("video/") {
-                        file_metadata::Type::Video
-                    } else if ftype.starts_with("audio/") {
-                        file_metadata::Type::Audio
-                    } else if path.extension().unwrap_or_default() == "apk" {
-                        file_metadata::Type::App
-                    } else {
-                        file_metadata::Type::Unknown
-                    };
-
-                    info!("File type to send: {}", ftype);
-                    let fname = path
-                        .file_name()
-                        .ok_or_else(|| anyhow!("Failed to get file_name for {f}"))?;
                     let fmeta = FileMetadata {
                         payload_id: Some(rand::rng().random::<i64>()),
-                        name: Some(fname.to_os_string().into_string().unwrap()),
-                        size: Some(fmetadata.size() as i64),
-                        mime_type: Some(ftype),
-                        r#type: Some(meta_type.into()),
-                        ..Default::default()
+                        name: Some(pf.name.clone()),
+                        size: Some(pf.size as i64),
+                        mime_type: Some(pf.mime_type.clone()),
+                        r#type: Some(pf.meta_type.into()),
+                        parent_folder: pf.parent_folder.clone(),
+                        id: Some(pf.attachment_id),
                     };
                     transferred_files.insert(
                         fmeta.payload_id(),
                         InternalFileInfo {
                             payload_id: fmeta.payload_id(),
-                            file_url: path.to_path_buf(),
+                            file_url: pf.path.clone(),
                             bytes_transferred: 0,
                             total_size: fmeta.size(),
                             file: Some(file),
+                            parent_folder: fmeta.parent_folder.clone(),
+                            resume_key: None,
                         },
                     );
                     file_metadata.push(fmeta);
-                    total_to_send += fmetadata.size();
+                    total_to_send += pf.size;
                 }
             }
+            OutboundPayload::Text(text) => {
+                let tmeta = gen_text_metadata(text, text_metadata::Type::Text);
+                text_payloads.insert(
+                    tmeta.payload_id(),
+                    TextPayloadInfo::Text(tmeta.payload_id()),
+                );
+                total_to_send += tmeta.size() as u64;
+                text_metadata.push(tmeta);
+            }
+            OutboundPayload::Url(url) => {
+                let tmeta = gen_text_metadata(url, text_metadata::Type::Url);
+                text_payloads.insert(tmeta.payload_id(), TextPayloadInfo::Url(tmeta.payload_id()));
+                total_to_send += tmeta.size() as u64;
+                text_metadata.push(tmeta);
+            }
+            OutboundPayload::WifiCredentials {
+                ssid,
+                security_type,
+                ..
+            } => {
+                let wmeta = WifiCredentialsMetadata {
+                    ssid: Some(ssid.to_owned()),
+                    security_type: Some(
+                        wifi_credentials_metadata::SecurityType::from(security_type).into(),
+                    ),
+                    payload_id: Some(rand::rng().random::<i64>()),
+                    id: Some(rand::rng().random::<i64>()),
+                };
+                text_payloads.insert(
+                    wmeta.payload_id(),
+                    TextPayloadInfo::Wifi((wmeta.payload_id(), ssid.to_owned())),
+                );
+                total_to_send += self.payload.bytes_payload().unwrap_or_default().len() as u64;
+                wifi_credentials_metadata.push(wmeta);
+            }
         }
 
+        let items = file_metadata
+            .iter()
+            .map(|m| TransferItem {
+                payload_id: m.payload_id(),
+                name: m.name().to_owned(),
+                size: m.size(),
+                text_type: None,
+                text_payload: None,
+            })
+            .chain(text_metadata.iter().map(|m| TransferItem {
+                payload_id: m.payload_id(),
+                name: m.text_title().to_owned(),
+                size: m.size(),
+                text_type: text_payloads.get(&m.payload_id()).map(|t| t.get_type()),
+                text_payload: None,
+            }))
+            .chain(wifi_credentials_metadata.iter().map(|m| TransferItem {
+                payload_id: m.payload_id(),
+                name: m.ssid().to_owned(),
+                size: 0,
+                text_type: Some(TextPayloadType::Wifi),
+                text_payload: None,
+            }))
+            .collect();
+
         self.update_state(
             |e| {
                 if let Some(tmd) = e.transfer_metadata.as_mut() {
                     tmd.total_bytes = total_to_send;
+                    tmd.items = items;
                 }
                 e.transferred_files = transferred_files;
+                e.text_payloads = text_payloads;
             },
             false,
         )
@@ -719,6 +938,11 @@ impl OutboundRequest {
                 r#type: Some(sharing_nearby::v1_frame::FrameType::Introduction.into()),
                 introduction: Some(IntroductionFrame {
                     file_metadata,
+                    text_metadata,
+                    wifi_credentials_metadata,
+                    rquickshare_extension: Some(IntroductionExtension {
+                        supports_resume: Some(true),
+                    }),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -734,178 +958,87 @@ impl OutboundRequest {
         &mut self,
         v1_frame: &sharing_nearby::V1Frame,
     ) -> Result<(), anyhow::Error> {
-        if v1_frame.r#type() != sharing_nearby::v1_frame::FrameType::Response
-            || v1_frame.connection_response.is_none()
-        {
+        if v1_frame.r#type() != sharing_nearby::v1_frame::FrameType::Response {
             return Err(anyhow!("Missing required fields"));
         }
 
-        match v1_frame.connection_response.as_ref().unwrap().status() {
+        let connection_response = v1_frame
+            .connection_response
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing required fields"))?;
+        match connection_response.status() {
             sharing_nearby::connection_response_frame::Status::Accept => {
+                // Skip what the receiver already got from an interrupted transfer
+                let mut resumed_bytes = 0;
+                let resume_offsets = connection_response
+                    .rquickshare_extension
+                    .as_ref()
+                    .map(|ext| ext.resume_offsets.as_slice())
+                    .unwrap_or_default();
+                for ro in resume_offsets {
+                    let Some(mfi) = self.state.transferred_files.get_mut(&ro.payload_id()) else {
+                        continue;
+                    };
+                    if ro.offset() <= 0 || ro.offset() >= mfi.total_size {
+                        continue;
+                    }
+
+                    if let Some(file) = mfi.file.as_mut() {
+                        file.seek(SeekFrom::Start(ro.offset() as u64))?;
+                        mfi.bytes_transferred = ro.offset();
+                        resumed_bytes += ro.offset() as u64;
+                        info!("Resuming {:?} at {} bytes", mfi.file_url, ro.offset());
+                    }
+                }
+
                 info!("State is now State::SendingFiles");
                 self.update_state(
                     |e| {
                         e.state = State::SendingFiles;
+                        if let Some(tmd) = e.transfer_metadata.as_mut() {
+                            tmd.ack_bytes += resumed_bytes;
+                        }
                     },
                     true,
                 )
                 .await;
 
-                // TODO - Handle sending Text
-                let ids: Vec<i64> = self.state.transferred_files.keys().cloned().collect();
-                info!("We are sending: {:?}", ids);
-                let mut ids_iter = ids.into_iter();
-                // Loop through all files
-                loop {
-                    let current = match ids_iter.next() {
-                        Some(i) => i,
-                        None => {
-                            info!("All files have been transferred");
-                            self.update_state(
-                                |e| {
-                                    e.state = State::Finished;
-                                },
-                                true,
-                            )
-                            .await;
-                            self.disconnection().await?;
-                            // Breaking instead of NotAnError to allow peacefull termination
-                            break;
-                        }
-                    };
-
-                    // Loop until we reached end of file
-                    loop {
-                        // Workaround to limit scope of the immutable borrow on self
-                        let (curr_state, buffer, bytes_read) = {
-                            let curr_state = match self.state.transferred_files.get(&current) {
-                                Some(s) => s,
-                                None => break,
-                            };
-
-                            info!("> Currently sending {:?}", curr_state.file_url);
-                            if curr_state.bytes_transferred == curr_state.total_size {
-                                debug!("File {current} finished");
-                                self.update_state(
-                                    |e| {
-                                        e.transferred_files.remove(&current);
-                                    },
-                                    false,
-                                )
-                                .await;
-                                break;
+                if let Some(text_info) = self.state.text_payloads.values().next().cloned() {
+                    let data = self
+                        .payload
+                        .bytes_payload()
+                        .ok_or_else(|| anyhow!("Missing bytes payload for text transfer"))?;
+                    let data_len = data.len();
+
+                    self.send_bytes_payload(text_info.get_i64_value(), data)
+                        .await?;
+                    info!("Text has been transferred");
+                    self.update_state(
+                        |e| {
+                            if let Some(tmd) = e.transfer_metadata.as_mut() {
+                                tmd.ack_bytes += data_len as u64;
                             }
-
-                            if curr_state.file.is_none() {
-                                warn!("File {current} is none");
-                                break;
-                            }
-
-                            let mut buffer = vec![0u8; 512 * 1024];
-                            let bytes_read = curr_state.file.as_ref().unwrap().read(&mut buffer)?;
-
-                            (
-                                InternalFileInfo {
-                                    payload_id: curr_state.payload_id,
-                                    file_url: curr_state.file_url.clone(),
-                                    bytes_transferred: curr_state.bytes_transferred,
-                                    total_size: curr_state.total_size,
-                                    file: None,
-                                },
-                                buffer,
-                                bytes_read,
-                            )
-                        };
-
-                        let sending_buffer = buffer[..bytes_read].to_vec();
-                        info!(
-                            "> File ready: {bytes_read} bytes && {} && left to send: {} with current offset: {}",
-                            sending_buffer.len(),
-                            curr_state.total_size - curr_state.bytes_transferred,
-							curr_state.bytes_transferred
-                        );
-
-                        let payload_header = PayloadHeader {
-                            id: Some(current),
-                            r#type: Some(payload_header::PayloadType::File.into()),
-                            total_size: Some(curr_state.total_size),
-                            is_sensitive: Some(false),
-                            file_name: curr_state
-                                .file_url
-                                .file_name()
-                                .map(|name| name.to_string_lossy().into_owned()),
-                            ..Default::default()
-                        };
-
-                        let wrapper = location_nearby_connections::OfflineFrame {
-							version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
-							v1: Some(location_nearby_connections::V1Frame {
-								r#type: Some(
-									location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
-								),
-								payload_transfer: Some(PayloadTransferFrame {
-									packet_type: Some(PacketType::Data.into()),
-									payload_chunk: Some(PayloadChunk {
-										offset: Some(curr_state.bytes_transferred),
-										flags: Some(0),
-										body: Some(buffer[..bytes_read].to_vec()),
-									}),
-									payload_header: Some(payload_header.clone()),
-									..Default::default()
-								}),
-								..Default::default()
-							}),
-						};
-
-                        self.encrypt_and_send(&wrapper).await?;
-                        self.update_state(
-                            |e| {
-                                if let Some(mu) = e.transferred_files.get_mut(&current) {
-                                    mu.bytes_transferred += bytes_read as i64;
-                                }
-
-                                if let Some(tmd) = e.transfer_metadata.as_mut() {
-                                    tmd.ack_bytes += bytes_read as u64;
-                                }
-                            },
-                            true,
-                        )
-                        .await;
-
-                        // If we just sent the last bytes of the file, mark it as finished
-                        if curr_state.bytes_transferred + bytes_read as i64 == curr_state.total_size
-                        {
-                            debug!(
-                                "File {current} finished, curr offset: {} over total: {}",
-                                curr_state.bytes_transferred + bytes_read as i64,
-                                curr_state.total_size
-                            );
-
-                            let wrapper = location_nearby_connections::OfflineFrame {
-								version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
-								v1: Some(location_nearby_connections::V1Frame {
-									r#type: Some(
-										location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
-									),
-									payload_transfer: Some(PayloadTransferFrame {
-										packet_type: Some(PacketType::Data.into()),
-										payload_chunk: Some(PayloadChunk {
-											offset: Some(curr_state.total_size),
-											flags: Some(1), // lastChunk
-											body: Some(vec![]),
-										}),
-										payload_header: Some(payload_header),
-										..Default::default()
-									}),
-									..Default::default()
-								}),
-							};
-
-                            self.encrypt_and_send(&wrapper).await?;
-                            break;
-                        }
-                    }
+                        },
+                        true,
+                    )
+                    .await;
+                    // Nothing queued, the next handle() will ask to disconnect
+                    return Ok(());
                 }
+
+                // Keep the order of the introduction, the chunks are then sent
+                // from handle() so that control messages can be processed meanwhile.
+                let ids: Vec<i64> = self
+                    .state
+                    .transfer_metadata
+                    .as_ref()
+                    .map(|tmd| tmd.items.iter().map(|i| i.payload_id).collect())
+                    .unwrap_or_default();
+                self.send_queue = ids
+                    .into_iter()
+                    .filter(|id| self.state.transferred_files.contains_key(id))
+                    .collect();
+                info!("We are sending: {:?}", self.send_queue);
             }
             sharing_nearby::connection_response_frame::Status::Reject
             | sharing_nearby::connection_response_frame::Status::NotEnoughSpace
@@ -913,11 +1046,17 @@ impl OutboundRequest {
             | sharing_nearby::connection_response_frame::Status::TimedOut => {
                 warn!(
                     "Cannot process: consent denied: {:?}",
-                    v1_frame.connection_response.as_ref().unwrap().status()
+                    connection_response.status()
                 );
+                let timed_out = connection_response.status()
+                    == sharing_nearby::connection_response_frame::Status::TimedOut;
                 self.update_state(
                     |e| {
-                        e.state = State::Disconnected;
+                        e.state = if timed_out {
+                            State::TimedOut
+                        } else {
+                            State::Disconnected
+                        };
                     },
                     true,
                 )
@@ -942,92 +1081,490 @@ impl OutboundRequest {
         Ok(())
     }
 
-    async fn disconnection(&mut self) -> Result<(), anyhow::Error> {
-        let frame = location_nearby_connections::OfflineFrame {
+    async fn send_next_chunk(&mut self) -> Result<(), anyhow::Error> {
+        let Some(&current) = self.send_queue.front() else {
+            // Only finished once the receiver confirms it got everything
+            info!("Everything has been sent, waiting for the receiver");
+            self.disconnect_deadline = Some(Instant::now() + self.keep_alive.timeout);
+            return self
+                .send_disconnection(DisconnectionFrame {
+                    request_safe_to_disconnect: Some(true),
+                    ..Default::default()
+                })
+                .await;
+        };
+
+        let Some(curr_state) = self.state.transferred_files.get(&current) else {
+            // Cancelled or errored in the meantime
+            self.send_queue.pop_front();
+            return Ok(());
+        };
+
+        info!("> Currently sending {:?}", curr_state.file_url);
+        let payload_header = PayloadHeader {
+            id: Some(current),
+            r#type: Some(payload_header::PayloadType::File.into()),
+            total_size: Some(curr_state.total_size),
+            is_sensitive: Some(false),
+            file_name: curr_state
+                .file_url
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+            parent_folder: curr_state.parent_folder.clone(),
+        };
+        let offset = curr_state.bytes_transferred;
+        let total_size = curr_state.total_size;
+
+        if offset >= total_size {
+            debug!(
+                "File {current} finished, curr offset: {} over total: {}",
+                offset, curr_state.total_size
+            );
+            self.send_file_chunk(payload_header, offset, 1, vec![])
+                .await?;
+            self.sent_sizes.insert(current, total_size);
+            self.state.transferred_files.remove(&current);
+            self.send_queue.pop_front();
+            return Ok(());
+        }
+
+        let mut buffer = vec![0u8; 512 * 1024];
+        let read = match curr_state.file.as_ref() {
+            Some(mut file) => file.read(&mut buffer),
+            None => Err(std::io::Error::other("file is not opened")),
+        };
+        let bytes_read = match read {
+            Ok(n) if n > 0 => n,
+            r => {
+                // Only this file is lost, let the receiver know and go on
+                error!(
+                    "Failed to read {:?}: {:?}",
+                    curr_state.file_url,
+                    r.map(|_| "file is shorter than announced")
+                );
+                self.send_control_message(
+                    current,
+                    control_message::EventType::PayloadError,
+                    offset,
+                )
+                .await?;
+                self.drop_payload(current).await;
+                return Ok(());
+            }
+        };
+        buffer.truncate(bytes_read);
+
+        info!(
+            "> File ready: {bytes_read} bytes && left to send: {} with current offset: {}",
+            curr_state.total_size - offset,
+            offset
+        );
+        self.send_file_chunk(payload_header, offset, 0, buffer)
+            .await?;
+
+        let peer_acks = self.peer_acks;
+        self.update_state(
+            |e| {
+                if let Some(mu) = e.transferred_files.get_mut(&current) {
+                    mu.bytes_transferred += bytes_read as i64;
+                }
+
+                // Otherwise the progress is driven by the acks
+                if !peer_acks {
+                    if let Some(tmd) = e.transfer_metadata.as_mut() {
+                        tmd.ack_bytes += bytes_read as u64;
+                    }
+                }
+            },
+            true,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    async fn send_file_chunk(
+        &mut self,
+        payload_header: PayloadHeader,
+        offset: i64,
+        flags: i32,
+        body: Vec<u8>,
+    ) -> Result<(), anyhow::Error> {
+        let wrapper = location_nearby_connections::OfflineFrame {
             version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
             v1: Some(location_nearby_connections::V1Frame {
                 r#type: Some(
-                    location_nearby_connections::v1_frame::FrameType::Disconnection.into(),
+                    location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
                 ),
-                disconnection: Some(location_nearby_connections::DisconnectionFrame {
+                payload_transfer: Some(PayloadTransferFrame {
+                    packet_type: Some(PacketType::Data.into()),
+                    payload_chunk: Some(PayloadChunk {
+                        offset: Some(offset),
+                        flags: Some(flags),
+                        body: Some(body),
+                    }),
+                    payload_header: Some(payload_header),
                     ..Default::default()
                 }),
                 ..Default::default()
             }),
         };
 
-        if self.state.encryption_done {
-            self.encrypt_and_send(&frame).await
-        } else {
-            self.send_frame(frame.encode_to_vec()).await
+        self.encrypt_and_send(&wrapper).await
+    }
+
+    async fn send_control_message(
+        &mut self,
+        payload_id: i64,
+        event: control_message::EventType,
+        offset: i64,
+    ) -> Result<(), anyhow::Error> {
+        let wrapper = location_nearby_connections::OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(location_nearby_connections::V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::PayloadTransfer.into(),
+                ),
+                payload_transfer: Some(PayloadTransferFrame {
+                    packet_type: Some(PacketType::Control.into()),
+                    payload_header: Some(PayloadHeader {
+                        id: Some(payload_id),
+                        r#type: Some(payload_header::PayloadType::File.into()),
+                        ..Default::default()
+                    }),
+                    control_message: Some(ControlMessage {
+                        event: Some(event.into()),
+                        offset: Some(offset),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        };
+
+        self.encrypt_and_send(&wrapper).await
+    }
+
+    async fn process_control_message(
+        &mut self,
+        header: &PayloadHeader,
+        control: &ControlMessage,
+    ) -> Result<(), anyhow::Error> {
+        let payload_id = header.id();
+
+        match control.event() {
+            control_message::EventType::PayloadReceivedAck => {
+                trace!("Payload {payload_id} acked up to {}", control.offset());
+                self.peer_acks = true;
+                // Never trust the offsets to add up, it's only for the progress
+                self.acked_offsets
+                    .insert(payload_id, control.offset().max(0));
+
+                let acked = self
+                    .acked_offsets
+                    .values()
+                    .fold(0u64, |acc, o| acc.saturating_add(*o as u64));
+                self.update_state(
+                    |e| {
+                        if let Some(tmd) = e.transfer_metadata.as_mut() {
+                            tmd.ack_bytes = acked;
+                        }
+                    },
+                    true,
+                )
+                .await;
+            }
+            control_message::EventType::PayloadCanceled
+            | control_message::EventType::PayloadError => {
+                warn!(
+                    "Payload {payload_id} stopped by the receiver: {:?}",
+                    control.event()
+                );
+                self.drop_payload(payload_id).await;
+            }
+            control_message::EventType::UnknownEventType => {
+                warn!("Unknown control message for payload {payload_id}");
+            }
         }
+
+        Ok(())
+    }
+
+    async fn cancel_payload(&mut self, payload_id: i64) -> Result<(), anyhow::Error> {
+        let Some(offset) = self
+            .state
+            .transferred_files
+            .get(&payload_id)
+            .map(|mfi| mfi.bytes_transferred)
+        else {
+            warn!("Cannot cancel unknown (or already sent) payload {payload_id}");
+            return Ok(());
+        };
+
+        // Files are only known once the introduction was sent, so the
+        // receiver always knows about this payload.
+        info!("Cancelling payload {payload_id}");
+        self.send_control_message(
+            payload_id,
+            control_message::EventType::PayloadCanceled,
+            offset,
+        )
+        .await?;
+        self.drop_payload(payload_id).await;
+
+        Ok(())
+    }
+
+    // Stop sending a single file, the others are still sent
+    async fn drop_payload(&mut self, payload_id: i64) {
+        let Some(mfi) = self.state.transferred_files.remove(&payload_id) else {
+            return;
+        };
+
+        let sent = self
+            .acked_offsets
+            .get(&payload_id)
+            .copied()
+            .unwrap_or(mfi.bytes_transferred);
+        self.update_state(
+            |e| {
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.total_bytes = tmd
+                        .total_bytes
+                        .saturating_sub((mfi.total_size - sent) as u64);
+                }
+            },
+            true,
+        )
+        .await;
     }
 
-    async fn finalize_key_exchange(
+    async fn cancel(&mut self, origin: CancelOrigin) -> Result<(), anyhow::Error> {
+        self.update_state(
+            |e| {
+                e.state = State::Cancelled;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.cancelled_by = Some(origin);
+                }
+            },
+            true,
+        )
+        .await;
+
+        self.disconnection().await
+    }
+
+    async fn process_bandwidth_upgrade(
         &mut self,
-        raw_peer_key: GenericPublicKey,
+        bwu: &BandwidthUpgradeNegotiationFrame,
     ) -> Result<(), anyhow::Error> {
-        let peer_p256_key = raw_peer_key
-            .ec_p256_public_key
-            .ok_or_else(|| anyhow!("Missing required fields"))?;
+        debug!("Received bandwidth upgrade event: {:?}", bwu.event_type());
+        match bwu.event_type() {
+            EventType::UpgradePathAvailable => {
+                let info = bwu.upgrade_path_info.clone().unwrap_or_default();
+                self.upgrade_to(info).await?;
+            }
+            EventType::LastWriteToPriorChannel => {
+                self.encrypt_and_send(&bwu_frame(EventType::SafeToClosePriorChannel, None))
+                    .await?;
+                if !self.bwu.switch_writes(&mut self.socket) {
+                    warn!("Prior channel closing without an upgraded one");
+                }
+            }
+            EventType::SafeToClosePriorChannel => {
+                if self.bwu.switch_reads() {
+                    info!("Bandwidth upgrade done");
+                }
+            }
+            _ => {
+                warn!("Unexpected bandwidth upgrade event: {:?}", bwu.event_type());
+            }
+        }
 
-        let mut bytes = vec![0x04];
-        // Ensure no more than 32 bytes for the keys
-        if peer_p256_key.x.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.x[peer_p256_key.x.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.x);
+        Ok(())
+    }
+
+    async fn upgrade_to(&mut self, info: UpgradePathInfo) -> Result<(), anyhow::Error> {
+        let Some(addr) = wifi_lan_address(&info).filter(|_| self.socket.local_addr().is_some())
+        else {
+            warn!("Unsupported upgrade path: {:?}", info.medium());
+            return self
+                .encrypt_and_send(&bwu_frame(EventType::UpgradeFailure, Some(info)))
+                .await;
+        };
+
+        info!("Upgrading the connection to {addr}");
+        let endpoint_id = String::from_utf8_lossy(&self.endpoint_id).to_string();
+        match connect_wifi_lan(addr, &endpoint_id, info.supports_client_introduction_ack()).await {
+            Ok(socket) => {
+                debug!("Upgraded socket introduced, draining the prior one");
+                self.bwu.socket = S::from_upgraded(socket);
+                self.encrypt_and_send(&bwu_frame(EventType::LastWriteToPriorChannel, None))
+                    .await
+            }
+            Err(e) => {
+                warn!("Bandwidth upgrade failed: {e}");
+                self.encrypt_and_send(&bwu_frame(EventType::UpgradeFailure, Some(info)))
+                    .await
+            }
         }
-        if peer_p256_key.y.len() > 32 {
-            bytes.extend_from_slice(&peer_p256_key.y[peer_p256_key.y.len() - 32..]);
-        } else {
-            bytes.extend_from_slice(&peer_p256_key.y);
+    }
+
+    async fn finished(&mut self) -> Result<(), anyhow::Error> {
+        info!("Transfer finished");
+        self.update_state(
+            |e| {
+                e.state = State::Finished;
+            },
+            true,
+        )
+        .await;
+
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
+    // The receiver went away after everything was sent but without acking our
+    // safe-to-disconnect request, as receivers not supporting it do. That's
+    // only a success if its acks show that it got every file in full.
+    async fn closed_after_sending(&mut self) -> Result<(), anyhow::Error> {
+        let all_acked = self.sent_sizes.iter().all(|(id, size)| {
+            self.acked_offsets
+                .get(id)
+                .is_some_and(|offset| offset >= size)
+        });
+        if all_acked {
+            return self.finished().await;
         }
 
-        let encoded_point = EncodedPoint::from_bytes(bytes)?;
-        let peer_key = PublicKey::from_encoded_point(&encoded_point).unwrap();
-        let priv_key = self.state.private_key.as_ref().unwrap();
+        warn!("The receiver disconnected before confirming it got everything");
+        self.update_state(
+            |e| {
+                e.state = State::Disconnected;
+            },
+            true,
+        )
+        .await;
 
-        let dhs = diffie_hellman(priv_key.to_nonzero_scalar(), peer_key.as_affine());
-        let derived_secret = Sha256::digest(dhs.raw_secret_bytes());
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
 
-        let mut ukey_info: Vec<u8> = vec![];
-        ukey_info.extend_from_slice(self.state.client_init_msg_data.as_ref().unwrap());
-        ukey_info.extend_from_slice(self.state.server_init_data.as_ref().unwrap());
+    async fn process_remote_disconnection(
+        &mut self,
+        disconnection: &DisconnectionFrame,
+    ) -> Result<(), anyhow::Error> {
+        if self.state.state == State::Finished {
+            debug!("Remote device disconnected");
+            return Err(anyhow!(crate::errors::AppError::NotAnError));
+        }
 
-        let auth_label = "UKEY2 v1 auth".as_bytes();
-        let next_label = "UKEY2 v1 next".as_bytes();
+        if self.disconnect_deadline.is_some() {
+            if disconnection.ack_safe_to_disconnect() {
+                return self.finished().await;
+            }
+            debug!("Remote device disconnected without ack");
+            return self.closed_after_sending().await;
+        }
 
-        let auth_string = hkdf_extract_expand(auth_label, &derived_secret, &ukey_info, 32)?;
-        let next_secret = hkdf_extract_expand(next_label, &derived_secret, &ukey_info, 32)?;
+        if disconnection.request_safe_to_disconnect() {
+            let _ = self
+                .send_disconnection(DisconnectionFrame {
+                    ack_safe_to_disconnect: Some(true),
+                    ..Default::default()
+                })
+                .await;
+        }
 
-        let salt_hex = "82AA55A0D397F88346CA1CEE8D3909B95F13FA7DEB1D4AB38376B8256DA85510";
-        let salt =
-            hex::decode(salt_hex).map_err(|e| anyhow!("Failed to decode salt_hex: {}", e))?;
+        // The remote is already gone, no need to send it our disconnection
+        info!("Transfer canceled, the remote device disconnected");
+        self.update_state(
+            |e| {
+                e.state = State::Cancelled;
+                if let Some(tmd) = e.transfer_metadata.as_mut() {
+                    tmd.cancelled_by = Some(CancelOrigin::Remote);
+                }
+            },
+            true,
+        )
+        .await;
+
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
+
+    async fn security_error(&mut self, err: &SecureChannelError) -> Result<(), anyhow::Error> {
+        error!("Security error, disconnecting: {err}");
+        // The frontend only knows about the transfer once it has metadata
+        let inform = self.state.transfer_metadata.is_some();
+        self.update_state(
+            |e| {
+                e.state = State::SecurityError;
+            },
+            inform,
+        )
+        .await;
+        let _ = self.disconnection().await;
 
-        let d2d_client = hkdf_extract_expand(&salt, &next_secret, "client".as_bytes(), 32)?;
-        let d2d_server = hkdf_extract_expand(&salt, &next_secret, "server".as_bytes(), 32)?;
+        Err(anyhow!(crate::errors::AppError::NotAnError))
+    }
 
-        let key_salt_hex = "BF9D2A53C63616D75DB0A7165B91C1EF73E537F2427405FA23610A4BE657642E";
-        let key_salt = hex::decode(key_salt_hex)
-            .map_err(|e| anyhow!("Failed to decode key_salt_hex: {}", e))?;
+    async fn disconnection(&mut self) -> Result<(), anyhow::Error> {
+        self.send_disconnection(DisconnectionFrame::default()).await
+    }
 
-        let client_key = hkdf_extract_expand(&key_salt, &d2d_client, "ENC:2".as_bytes(), 32)?;
-        let client_hmac_key = hkdf_extract_expand(&key_salt, &d2d_client, "SIG:1".as_bytes(), 32)?;
-        let server_key = hkdf_extract_expand(&key_salt, &d2d_server, "ENC:2".as_bytes(), 32)?;
-        let server_hmac_key = hkdf_extract_expand(&key_salt, &d2d_server, "SIG:1".as_bytes(), 32)?;
+    async fn send_disconnection(
+        &mut self,
+        disconnection: DisconnectionFrame,
+    ) -> Result<(), anyhow::Error> {
+        let frame = location_nearby_connections::OfflineFrame {
+            version: Some(location_nearby_connections::offline_frame::Version::V1.into()),
+            v1: Some(location_nearby_connections::V1Frame {
+                r#type: Some(
+                    location_nearby_connections::v1_frame::FrameType::Disconnection.into(),
+                ),
+                disconnection: Some(disconnection),
+                ..Default::default()
+            }),
+        };
+
+        if self.state.encryption_done {
+            self.encrypt_and_send(&frame).await
+        } else {
+            self.send_frame(frame.encode_to_vec()).await
+        }
+    }
+
+    async fn finalize_key_exchange(&mut self, raw_peer_key: &[u8]) -> Result<(), anyhow::Error> {
+        let cipher = self
+            .state
+            .cipher_commitment
+            .as_ref()
+            .ok_or_else(|| anyhow!("No handshake cipher negotiated"))?
+            .handshake_cipher();
+        let derived_secret = ukey2_derived_secret(
+            cipher,
+            self.state.private_key.as_ref(),
+            self.state.x25519_private_key.as_ref(),
+            raw_peer_key,
+        )?;
+
+        let channel = SecureChannel::new(
+            SecureChannelRole::Client,
+            &derived_secret,
+            self.state
+                .client_init_msg_data
+                .as_deref()
+                .unwrap_or_default(),
+            self.state.server_init_data.as_deref().unwrap_or_default(),
+        )?;
+        let pin_code = channel.pin_code();
 
         self.update_state(
             |e| {
-                e.decrypt_key = Some(server_key);
-                e.recv_hmac_key = Some(server_hmac_key);
-                e.encrypt_key = Some(client_key);
-                e.send_hmac_key = Some(client_hmac_key);
-                e.pin_code = Some(to_four_digit_string(&auth_string));
+                e.secure_channel = Some(channel);
+                e.pin_code = Some(pin_code.clone());
                 e.encryption_done = true;
 
                 if let Some(ref mut tm) = e.transfer_metadata {
-                    tm.pin_code = Some(to_four_digit_string(&auth_string));
+                    tm.pin_code = Some(pin_code);
                 }
             },
             true,
@@ -1057,11 +1594,20 @@ impl OutboundRequest {
         &mut self,
         frame: &sharing_nearby::Frame,
     ) -> Result<(), anyhow::Error> {
-        let frame_data = frame.encode_to_vec();
+        let payload_id = rand::rng().random_range(i64::MIN..i64::MAX);
+        self.send_bytes_payload(payload_id, frame.encode_to_vec())
+            .await
+    }
+
+    async fn send_bytes_payload(
+        &mut self,
+        payload_id: i64,
+        frame_data: Vec<u8>,
+    ) -> Result<(), anyhow::Error> {
         let body_size = frame_data.len();
 
         let payload_header = PayloadHeader {
-            id: Some(rand::rng().random_range(i64::MIN..i64::MAX)),
+            id: Some(payload_id),
             r#type: Some(payload_header::PayloadType::Bytes.into()),
             total_size: Some(body_size as i64),
             is_sensitive: Some(false),
@@ -1123,48 +1669,14 @@ impl OutboundRequest {
     }
 
     async fn encrypt_and_send(&mut self, frame: &OfflineFrame) -> Result<(), anyhow::Error> {
-        let d2d_msg = DeviceToDeviceMessage {
-            sequence_number: Some(self.get_server_seq_inc().await),
-            message: Some(frame.encode_to_vec()),
-        };
-
-        let key = self.state.encrypt_key.as_ref().unwrap();
-        let msg_data = d2d_msg.encode_to_vec();
-        let iv = gen_random(16);
-
-        let mut cipher = Cipher::new_256(&key[..AES_256_KEY_LEN].try_into().unwrap());
-        cipher.set_auto_padding(true);
-        let encrypted = cipher.cbc_encrypt(&iv, &msg_data);
-
-        let hb = HeaderAndBody {
-            body: encrypted,
-            header: Header {
-                encryption_scheme: EncScheme::Aes256Cbc.into(),
-                signature_scheme: SigScheme::HmacSha256.into(),
-                iv: Some(iv),
-                public_metadata: Some(
-                    GcmMetadata {
-                        r#type: Type::DeviceToDeviceMessage.into(),
-                        version: Some(1),
-                    }
-                    .encode_to_vec(),
-                ),
-                ..Default::default()
-            },
-        };
-
-        let mut hmac = HmacSha256::new_from_slice(self.state.send_hmac_key.as_ref().unwrap())?;
-        hmac.update(&hb.encode_to_vec());
-        let result = hmac.finalize();
-
-        let smsg = SecureMessage {
-            header_and_body: hb.encode_to_vec(),
-            signature: result.into_bytes().to_vec(),
-        };
-
-        self.send_frame(smsg.encode_to_vec()).await?;
-
-        Ok(())
+        let data = self
+            .state
+            .secure_channel
+            .as_mut()
+            .ok_or_else(|| anyhow!("Cannot encrypt before the end of the handshake"))?
+            .encrypt(&frame.encode_to_vec())?;
+
+        self.send_frame(data).await
     }
 
     async fn send_keepalive(&mut self, ack: bool) -> Result<(), anyhow::Error> {
@@ -1199,36 +1711,17 @@ impl OutboundRequest {
         prefixed_length.extend_from_slice(&length_bytes);
         prefixed_length.extend_from_slice(&data);
 
-        self.socket.write_all(&prefixed_length).await?;
-        self.socket.flush().await?;
+        // A sleeping remote would otherwise block us here forever
+        tokio::time::timeout(self.keep_alive.timeout, async {
+            self.socket.write_all(&prefixed_length).await?;
+            self.socket.flush().await
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out writing to the remote device"))??;
 
         Ok(())
     }
 
-    async fn get_server_seq_inc(&mut self) -> i32 {
-        self.update_state(
-            |e| {
-                e.server_seq += 1;
-            },
-            false,
-        )
-        .await;
-
-        self.state.server_seq
-    }
-
-    async fn get_client_seq_inc(&mut self) -> i32 {
-        self.update_state(
-            |e| {
-                e.client_seq += 1;
-            },
-            false,
-        )
-        .await;
-
-        self.state.client_seq
-    }
-
     async fn update_state<F>(&mut self, f: F, inform: bool)
     where
         F: FnOnce(&mut InnerState),
@@ -1245,6 +1738,7 @@ impl OutboundRequest {
             rtype: Some(crate::channel::TransferType::Outbound),
             state: Some(self.state.state.clone()),
             meta: self.state.transfer_metadata.clone(),
+            group_id: self.group_id.clone(),
             ..Default::default()
         });
         // Add a small sleep timer to allow the Tokio runtime to have
@@ -1253,3 +1747,158 @@ impl OutboundRequest {
         tokio::time::sleep(SANITY_DURATION).await;
     }
 }
+
+// Stable id for the same file (same path, size and modification time),
+// used by the receiver to resume an interrupted transfer.
+fn gen_attachment_id(path: &Path, metadata: &std::fs::Metadata) -> i64 {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+    let mut hasher = Sha256::new();
+    hasher.update(path.as_os_str().as_encoded_bytes());
+    hasher.update(metadata.size().to_be_bytes());
+    hasher.update(metadata.mtime().to_be_bytes());
+    hasher.update(metadata.mtime_nsec().to_be_bytes());
+    let digest = hasher.finalize();
+
+    i64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+fn gen_text_metadata(content: &str, ttype: text_metadata::Type) -> TextMetadata {
+    // The title is only a preview shown to the receiver before
+    // accepting, the full content is sent as a Bytes payload.
+    let title: String = content
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .take(TEXT_TITLE_MAX_CHARS)
+        .collect();
+
+    TextMetadata {
+        text_title: Some(title),
+        r#type: Some(ttype.into()),
+        payload_id: Some(rand::rng().random::<i64>()),
+        size: Some(content.len() as i64),
+        id: Some(rand::rng().random::<i64>()),
+    }
+}
+
+/// File to send, as described in the introduction.
+#[derive(Debug)]
+pub struct PreparedFile {
+    path: PathBuf,
+    parent_folder: Option<String>,
+    name: String,
+    size: u64,
+    mime_type: String,
+    meta_type: file_metadata::Type,
+    attachment_id: i64,
+}
+
+// Read the metadata of everything to send, once for all the devices
+// the files are sent to.
+pub fn prepare_files(paths: &[String]) -> Vec<PreparedFile> {
+    let mut prepared = Vec::new();
+
+    for (path, parent_folder) in collect_files(paths) {
+        let fmetadata = match std::fs::metadata(&path) {
+            Ok(_fm) => _fm,
+            Err(e) => {
+                error!("Failed to get metadata for: {}: {:?}", path.display(), e);
+                continue;
+            }
+        };
+        let name = match path.file_name() {
+            Some(n) => n.to_string_lossy().into_owned(),
+            None => {
+                error!("Failed to get file_name for {}", path.display());
+                continue;
+            }
+        };
+
+        let mime_type = mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .to_string();
+
+        let meta_type = if mime_type.starts_with("image/") {
+            file_metadata::Type::Image
+        } else if mime_type.starts_with("video/") {
+            file_metadata::Type::Video
+        } else if mime_type.starts_with("audio/") {
+            file_metadata::Type::Audio
+        } else if path.extension().unwrap_or_default() == "apk" {
+            file_metadata::Type::App
+        } else {
+            file_metadata::Type::Unknown
+        };
+
+        info!("File type to send: {}", mime_type);
+        prepared.push(PreparedFile {
+            attachment_id: gen_attachment_id(&path, &fmetadata),
+            size: fmetadata.size(),
+            path,
+            parent_folder,
+            name,
+            mime_type,
+            meta_type,
+        });
+    }
+
+    prepared
+}
+
+// Expand the paths to send into a list of files, directories are walked
+// recursively and each file is returned along with its folder relative
+// to the parent of the shared directory (eg. 'Photos/2024').
+fn collect_files(paths: &[String]) -> Vec<(PathBuf, Option<String>)> {
+    let mut files = Vec::new();
+
+    for p in paths {
+        let path = Path::new(p);
+        if path.is_file() {
+            files.push((path.to_path_buf(), None));
+        } else if path.is_dir() {
+            let root = match path.file_name() {
+                Some(r) => r.to_string_lossy().into_owned(),
+                None => {
+                    warn!("Cannot send a directory without name: {}", p);
+                    continue;
+                }
+            };
+
+            collect_dir_files(path, &root, &mut files);
+        } else {
+            warn!("Path is not a file nor a directory: {}", p);
+        }
+    }
+
+    files
+}
+
+fn collect_dir_files(dir: &Path, relative: &str, files: &mut Vec<(PathBuf, Option<String>)>) {
+    let mut entries: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(rd) => rd.filter_map(Result::ok).collect(),
+        Err(e) => {
+            error!("Failed to read directory: {}: {:?}", dir.display(), e);
+            return;
+        }
+    };
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        // file_type() doesn't follow symlinks, which avoids looping
+        // forever on a link pointing to one of its parents.
+        let ftype = match entry.file_type() {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        let path = entry.path();
+
+        if ftype.is_dir() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            collect_dir_files(&path, &format!("{relative}/{name}"), files);
+        } else if path.is_file() {
+            files.push((path, Some(relative.to_owned())));
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use prost::Message;
+use sha2::{Digest, Sha512};
+
+use super::UKEY2_CIPHERS;
+use crate::location_nearby_connections::payload_transfer_frame::PayloadChunk;
+use crate::location_nearby_connections::{v1_frame, ConnectionRequestFrame, OfflineFrame};
+use crate::securegcm::ukey2_alert::AlertType;
+use crate::securegcm::ukey2_client_init::CipherCommitment;
+use crate::securegcm::{ukey2_message, Ukey2ClientFinished, Ukey2ClientInit, Ukey2Message};
+use crate::utils::{DeviceType, RemoteDeviceInfo};
+
+// Biggest Bytes payload (the introduction, or a text) we accept
+const MAX_BYTES_PAYLOAD: i64 = 5 * 1024 * 1024;
+
+/// Data received from the remote device which doesn't follow the protocol.
+///
+/// None of this is recoverable, the connection is to be closed.
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    Decode(prost::DecodeError),
+    MissingField(&'static str),
+    UnexpectedFrame(v1_frame::FrameType),
+    InvalidEndpointInfo,
+    // The UKEY2 handshake can't go on, the remote is to be sent this alert
+    Ukey2(AlertType),
+    // The ClientFinished isn't the one the client committed to
+    CommitmentMismatch,
+    InvalidSize(i64),
+    UnexpectedOffset { expected: i64, received: i64 },
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode(e) => write!(f, "malformed message: {e}"),
+            Self::MissingField(name) => write!(f, "missing required field: {name}"),
+            Self::UnexpectedFrame(t) => write!(f, "unexpected frame type: {t:?}"),
+            Self::InvalidEndpointInfo => write!(f, "invalid endpoint info"),
+            Self::Ukey2(alert) => write!(f, "UKey2 handshake failed: {alert:?}"),
+            Self::CommitmentMismatch => write!(f, "UKey2: cipher_commitment != sha512"),
+            Self::InvalidSize(size) => write!(f, "invalid payload size: {size}"),
+            Self::UnexpectedOffset { expected, received } => {
+                write!(
+                    f,
+                    "unexpected chunk offset {received} (expected {expected})"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<prost::DecodeError> for ProtocolError {
+    fn from(e: prost::DecodeError) -> Self {
+        Self::Decode(e)
+    }
+}
+
+/// First frame of a connection, which must be a ConnectionRequest
+/// describing the remote device.
+pub fn parse_connection_request(
+    data: &[u8],
+) -> Result<(ConnectionRequestFrame, RemoteDeviceInfo), ProtocolError> {
+    let frame = OfflineFrame::decode(data)?;
+    let v1_frame = frame.v1.ok_or(ProtocolError::MissingField("v1"))?;
+    if v1_frame.r#type() != v1_frame::FrameType::ConnectionRequest {
+        return Err(ProtocolError::UnexpectedFrame(v1_frame.r#type()));
+    }
+
+    let connection_request = v1_frame
+        .connection_request
+        .ok_or(ProtocolError::MissingField("connection_request"))?;
+    let endpoint_info = connection_request
+        .endpoint_info
+        .as_deref()
+        .ok_or(ProtocolError::MissingField("endpoint_info"))?;
+
+    // 17 bytes of header, then the length of the device name and the name
+    let device_name_length = *endpoint_info
+        .get(17)
+        .ok_or(ProtocolError::InvalidEndpointInfo)? as usize;
+    let device_name = endpoint_info
+        .get(18..18 + device_name_length)
+        .ok_or(ProtocolError::InvalidEndpointInfo)?;
+    let device_name =
+        std::str::from_utf8(device_name).map_err(|_| ProtocolError::InvalidEndpointInfo)?;
+
+    let raw_device_type = (endpoint_info[0] & 7) >> 1_usize;
+    let rdi = RemoteDeviceInfo {
+        name: device_name.to_string(),
+        device_type: DeviceType::from_raw_value(raw_device_type),
+    };
+
+    Ok((connection_request, rdi))
+}
+
+/// ClientInit of the UKEY2 handshake, along with the commitment of the
+/// strongest cipher both sides support.
+pub fn parse_ukey2_client_init(
+    data: &[u8],
+) -> Result<(Ukey2ClientInit, CipherCommitment), ProtocolError> {
+    let msg = Ukey2Message::decode(data)?;
+    if msg.message_type() != ukey2_message::Type::ClientInit {
+        return Err(ProtocolError::Ukey2(AlertType::BadMessageType));
+    }
+
+    let client_init = Ukey2ClientInit::decode(msg.message_data())
+        .map_err(|_| ProtocolError::Ukey2(AlertType::BadMessageData))?;
+    if client_init.version() != 1 {
+        return Err(ProtocolError::Ukey2(AlertType::BadVersion));
+    }
+
+    if client_init.random().len() != 32 {
+        return Err(ProtocolError::Ukey2(AlertType::BadRandom));
+    }
+
+    let commitment = UKEY2_CIPHERS
+        .iter()
+        .find_map(|cipher| {
+            client_init
+                .cipher_commitments
+                .iter()
+                .find(|c| c.handshake_cipher() == *cipher)
+        })
+        .cloned()
+        .ok_or(ProtocolError::Ukey2(AlertType::BadHandshakeCipher))?;
+
+    if client_init.next_protocol() != "AES_256_CBC-HMAC_SHA256" {
+        return Err(ProtocolError::Ukey2(AlertType::BadNextProtocol));
+    }
+
+    Ok((client_init, commitment))
+}
+
+/// Public key of the client, from its ClientFinished which has to be
+/// the one it committed to in its ClientInit.
+pub fn parse_ukey2_client_finish(
+    data: &[u8],
+    commitment: &CipherCommitment,
+) -> Result<Vec<u8>, ProtocolError> {
+    let msg = Ukey2Message::decode(data)?;
+    if msg.message_type() != ukey2_message::Type::ClientFinish {
+        return Err(ProtocolError::Ukey2(AlertType::BadMessageType));
+    }
+
+    if commitment.commitment() != Sha512::digest(data).as_slice() {
+        return Err(ProtocolError::CommitmentMismatch);
+    }
+
+    Ukey2ClientFinished::decode(msg.message_data())?
+        .public_key
+        .ok_or(ProtocolError::MissingField("public_key"))
+}
+
+/// Append a chunk of a Bytes payload of `total_size` bytes to what was
+/// received of it so far.
+pub fn append_bytes_chunk(
+    buffer: &mut Vec<u8>,
+    total_size: i64,
+    chunk: &PayloadChunk,
+) -> Result<(), ProtocolError> {
+    if !(0..=MAX_BYTES_PAYLOAD).contains(&total_size) {
+        return Err(ProtocolError::InvalidSize(total_size));
+    }
+
+    let expected = buffer.len() as i64;
+    if chunk.offset() != expected {
+        return Err(ProtocolError::UnexpectedOffset {
+            expected,
+            received: chunk.offset(),
+        });
+    }
+
+    let body = chunk.body();
+    if expected + body.len() as i64 > total_size {
+        return Err(ProtocolError::InvalidSize(expected + body.len() as i64));
+    }
+    buffer.extend_from_slice(body);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(offset: i64, body: &[u8]) -> PayloadChunk {
+        PayloadChunk {
+            offset: Some(offset),
+            body: Some(body.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_connection_request_truncated() {
+        for len in 0..20 {
+            let frame = OfflineFrame {
+                v1: Some(crate::location_nearby_connections::V1Frame {
+                    r#type: Some(v1_frame::FrameType::ConnectionRequest.into()),
+                    connection_request: Some(ConnectionRequestFrame {
+                        // Announces a 10 bytes name
+                        endpoint_info: Some([10u8; 19][..len].to_vec()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+
+            assert_eq!(
+                parse_connection_request(&frame.encode_to_vec()).unwrap_err(),
+                ProtocolError::InvalidEndpointInfo
+            );
+        }
+    }
+
+    #[test]
+    fn test_append_bytes_chunk() {
+        let mut buffer = Vec::new();
+        append_bytes_chunk(&mut buffer, 6, &chunk(0, b"abc")).unwrap();
+        append_bytes_chunk(&mut buffer, 6, &chunk(3, b"def")).unwrap();
+        assert_eq!(buffer, b"abcdef");
+
+        assert_eq!(
+            append_bytes_chunk(&mut buffer, 6, &chunk(6, b"g")),
+            Err(ProtocolError::InvalidSize(7))
+        );
+        assert_eq!(
+            append_bytes_chunk(&mut buffer, 6, &chunk(2, b"")),
+            Err(ProtocolError::UnexpectedOffset {
+                expected: 6,
+                received: 2
+            })
+        );
+        assert_eq!(
+            append_bytes_chunk(&mut Vec::new(), -1, &chunk(0, b"")),
+            Err(ProtocolError::InvalidSize(-1))
+        );
+    }
+}
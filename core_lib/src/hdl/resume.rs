@@ -0,0 +1,305 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+
+use super::info::InternalFileInfo;
+use crate::utils::write_atomic;
+
+// How long a partially received file is kept around, waiting for the
+// same sender to offer it again.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// How often the expired ones are looked for
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct ResumableFile {
+    info: InternalFileInfo,
+    saved_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // Partially received files of interrupted transfers, by resume key
+    files: HashMap<String, ResumableFile>,
+    // Every temporary file created and not yet finalized or removed
+    parts: BTreeSet<PathBuf>,
+}
+
+/// The temporary files of the transfers an RQS instance receives, and
+/// those of the interrupted ones it keeps to be resumed.
+///
+/// They're listed in `manifest` (if any) as they're created, so that
+/// the next run can remove them if this one wasn't stopped.
+#[derive(Debug, Default)]
+pub struct ResumeStore {
+    manifest: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+/// Key identifying a file offered by a given sender across connections,
+/// only meaningful if the sender said it supports resuming.
+pub(crate) fn resume_key(remote_name: &str, attachment_id: i64, size: i64) -> Option<String> {
+    if attachment_id == 0 {
+        return None;
+    }
+
+    Some(format!("{remote_name}/{attachment_id}/{size}"))
+}
+
+// The payload_id is part of the name so that two transfers of the same
+// file at the same time don't write into the same temporary file.
+pub(crate) fn get_part_path(dest: &Path, payload_id: i64) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    dest.with_file_name(format!(".{}.{}.part", name, payload_id as u64))
+}
+
+impl ResumeStore {
+    pub fn new(manifest: Option<PathBuf>) -> Self {
+        Self {
+            manifest,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Remove the temporary files listed by a previous run which wasn't
+    /// stopped, as what could be resumed was only known in memory.
+    pub(crate) fn remove_leftovers(&self) {
+        let Some(manifest) = &self.manifest else {
+            return;
+        };
+
+        let leftovers: Vec<PathBuf> = match std::fs::read(manifest) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable {}: {e}", manifest.display());
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                error!("Couldn't read {}: {e}", manifest.display());
+                return;
+            }
+        };
+
+        // Only what looks like ours, in case the list was tampered with
+        for part in leftovers
+            .iter()
+            .filter(|p| p.file_name().is_some_and(is_part_file))
+        {
+            remove_file(part);
+        }
+
+        let inner = self.inner.lock().unwrap();
+        self.persist(&inner);
+    }
+
+    /// Create the temporary file a payload is received into.
+    pub(crate) fn create_part(&self, dest: &Path, payload_id: i64) -> std::io::Result<File> {
+        let part = get_part_path(dest, payload_id);
+        // Listed first, so that it's never left behind unlisted
+        let mut inner = self.inner.lock().unwrap();
+        if inner.parts.insert(part.clone()) {
+            self.persist(&inner);
+        }
+
+        File::create(part)
+    }
+
+    /// Move the fully received temporary file of `info` to `dest`.
+    pub(crate) fn finalize_part(
+        &self,
+        info: &InternalFileInfo,
+        dest: &Path,
+    ) -> std::io::Result<()> {
+        let part = get_part_path(&info.file_url, info.payload_id);
+        std::fs::rename(&part, dest)?;
+        self.forget_part(&part);
+
+        Ok(())
+    }
+
+    /// Remove the temporary file of `info`, it won't ever be resumed.
+    pub(crate) fn remove_part(&self, info: &InternalFileInfo) {
+        let part = get_part_path(&info.file_url, info.payload_id);
+        remove_file(&part);
+        self.forget_part(&part);
+    }
+
+    /// Keep a partially received file so that it can be resumed later.
+    pub(crate) fn save(&self, key: String, mut info: InternalFileInfo) {
+        info.file = None;
+
+        debug!(
+            "Keeping {:?} ({} bytes) for resume",
+            info.file_url, info.bytes_transferred
+        );
+        let previous = self.inner.lock().unwrap().files.insert(
+            key,
+            ResumableFile {
+                info,
+                saved_at: Instant::now(),
+            },
+        );
+        if let Some(previous) = previous {
+            self.remove_part(&previous.info);
+        }
+    }
+
+    /// Take back a partially received file, moving its temporary file
+    /// to the one expected for the new payload_id.
+    pub(crate) fn take(&self, key: &str, payload_id: i64) -> Option<InternalFileInfo> {
+        let mut info = self.inner.lock().unwrap().files.remove(key)?.info;
+
+        let old_part = get_part_path(&info.file_url, info.payload_id);
+        let new_part = get_part_path(&info.file_url, payload_id);
+        let on_disk = std::fs::metadata(&old_part).map(|m| m.len()).unwrap_or(0);
+        if on_disk < info.bytes_transferred as u64 {
+            warn!("Couldn't resume {:?}, starting over", info.file_url);
+            self.remove_part(&info);
+            return None;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.parts.insert(new_part.clone());
+        self.persist(&inner);
+        let renamed = std::fs::rename(&old_part, &new_part);
+        let gone = if renamed.is_ok() {
+            &old_part
+        } else {
+            &new_part
+        };
+        inner.parts.remove(gone);
+        self.persist(&inner);
+        drop(inner);
+
+        if let Err(e) = renamed {
+            warn!("Couldn't resume {:?}, starting over: {e}", info.file_url);
+            self.remove_part(&info);
+            return None;
+        }
+
+        info.payload_id = payload_id;
+        Some(info)
+    }
+
+    /// Remove every partially received file kept for resume.
+    pub(crate) fn clear(&self) {
+        let files: Vec<_> = self.inner.lock().unwrap().files.drain().collect();
+        for (_, rf) in files {
+            self.remove_part(&rf.info);
+        }
+    }
+
+    /// Remove the files kept for too long, until `ctk` is cancelled.
+    pub(crate) async fn expire(&self, ctk: CancellationToken) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ctk.cancelled() => break,
+                _ = interval.tick() => self.purge_expired(),
+            }
+        }
+    }
+
+    fn purge_expired(&self) {
+        let expired: Vec<ResumableFile> = {
+            let mut inner = self.inner.lock().unwrap();
+            let keys: Vec<String> = inner
+                .files
+                .iter()
+                .filter(|(_, rf)| rf.saved_at.elapsed() >= RESUME_TIMEOUT)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.iter().filter_map(|k| inner.files.remove(k)).collect()
+        };
+
+        for rf in expired {
+            debug!("Not keeping {:?} for resume anymore", rf.info.file_url);
+            self.remove_part(&rf.info);
+        }
+    }
+
+    fn forget_part(&self, part: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.parts.remove(part) {
+            self.persist(&inner);
+        }
+    }
+
+    fn persist(&self, inner: &Inner) {
+        let Some(manifest) = &self.manifest else {
+            return;
+        };
+
+        if let Err(e) = write_atomic(manifest, &inner.parts) {
+            error!("Couldn't write {}: {e}", manifest.display());
+        }
+    }
+}
+
+// Named as by get_part_path: .{name}.{payload_id}.part
+fn is_part_file(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.')
+        && name
+            .strip_suffix(".part")
+            .and_then(|n| n.rsplit_once('.'))
+            .is_some_and(|(_, id)| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn remove_file(path: &Path) {
+    if path.exists() {
+        debug!("Removing leftover file: {:?}", path);
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Couldn't remove {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_part_file() {
+        let part = get_part_path(Path::new("/tmp/video.mp4"), -42);
+        assert!(is_part_file(part.file_name().unwrap()));
+
+        assert!(!is_part_file(OsStr::new("video.mp4")));
+        assert!(!is_part_file(OsStr::new(".video.mp4.part")));
+        assert!(!is_part_file(OsStr::new("notes.1.part")));
+    }
+
+    #[test]
+    fn test_remove_leftovers() {
+        let dir = std::env::temp_dir().join(format!("rqs_resume_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("parts.json");
+
+        // A run which didn't stop, and another file looking like a part
+        let crashed = ResumeStore::new(Some(manifest.clone()));
+        crashed.create_part(&dir.join("video.mp4"), 7).unwrap();
+        let unrelated = get_part_path(&dir.join("notes.txt"), 8);
+        std::fs::write(&unrelated, b"not ours").unwrap();
+
+        ResumeStore::new(Some(manifest.clone())).remove_leftovers();
+        let left = get_part_path(&dir.join("video.mp4"), 7).exists();
+        let kept = unrelated.exists();
+        let listed = std::fs::read_to_string(&manifest).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(!left);
+        assert!(kept);
+        assert_eq!(listed, "[]");
+    }
+}
@@ -0,0 +1,323 @@
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use libaes::{Cipher, AES_256_KEY_LEN};
+use prost::Message;
+use sha2::Sha256;
+
+use crate::securegcm::{DeviceToDeviceMessage, GcmMetadata, Type};
+use crate::securemessage::{EncScheme, Header, HeaderAndBody, SecureMessage, SigScheme};
+use crate::utils::{gen_random, hkdf_extract_expand, to_four_digit_string};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const AUTH_LABEL: &[u8] = b"UKEY2 v1 auth";
+const NEXT_LABEL: &[u8] = b"UKEY2 v1 next";
+const D2D_SALT_HEX: &str = "82AA55A0D397F88346CA1CEE8D3909B95F13FA7DEB1D4AB38376B8256DA85510";
+const KEY_SALT_HEX: &str = "BF9D2A53C63616D75DB0A7165B91C1EF73E537F2427405FA23610A4BE657642E";
+const IV_LEN: usize = 16;
+
+/// Side of the UKEY2 handshake, the client being the one initiating
+/// the connection (the sender).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureChannelRole {
+    Client,
+    Server,
+}
+
+/// Received message which wasn't sent by the remote device as is,
+/// meaning someone is tampering with the connection.
+#[derive(Debug, PartialEq)]
+pub enum SecureChannelError {
+    InvalidSignature,
+    // Replayed, dropped or reordered message
+    UnexpectedSequence { expected: i32, received: i32 },
+}
+
+impl std::fmt::Display for SecureChannelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidSignature => write!(f, "invalid message signature"),
+            Self::UnexpectedSequence { expected, received } => write!(
+                f,
+                "unexpected sequence number {received} (expected {expected})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SecureChannelError {}
+
+/// D2D channel resulting from a UKEY2 handshake.
+///
+/// Owns the keys of both directions and their sequence numbers, and turns
+/// messages into signed and encrypted SecureMessage (and back).
+pub struct SecureChannel {
+    role: SecureChannelRole,
+    auth_string: Vec<u8>,
+    encrypt_key: Vec<u8>,
+    send_hmac_key: Vec<u8>,
+    decrypt_key: Vec<u8>,
+    recv_hmac_key: Vec<u8>,
+    send_seq: i32,
+    recv_seq: i32,
+}
+
+// Never print the keys
+impl std::fmt::Debug for SecureChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureChannel")
+            .field("role", &self.role)
+            .field("send_seq", &self.send_seq)
+            .field("recv_seq", &self.recv_seq)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SecureChannel {
+    /// Derive the keys from the UKEY2 shared secret, and the serialized
+    /// ClientInit and ServerInit messages of the handshake.
+    pub fn new(
+        role: SecureChannelRole,
+        derived_secret: &[u8],
+        client_init_msg: &[u8],
+        server_init_msg: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        let mut ukey_info: Vec<u8> =
+            Vec::with_capacity(client_init_msg.len() + server_init_msg.len());
+        ukey_info.extend_from_slice(client_init_msg);
+        ukey_info.extend_from_slice(server_init_msg);
+
+        let auth_string = hkdf_extract_expand(AUTH_LABEL, derived_secret, &ukey_info, 32)?;
+        let next_secret = hkdf_extract_expand(NEXT_LABEL, derived_secret, &ukey_info, 32)?;
+
+        let salt =
+            hex::decode(D2D_SALT_HEX).map_err(|e| anyhow!("Failed to decode salt_hex: {}", e))?;
+        let d2d_client = hkdf_extract_expand(&salt, &next_secret, b"client", 32)?;
+        let d2d_server = hkdf_extract_expand(&salt, &next_secret, b"server", 32)?;
+
+        let key_salt = hex::decode(KEY_SALT_HEX)
+            .map_err(|e| anyhow!("Failed to decode key_salt_hex: {}", e))?;
+        let client_key = hkdf_extract_expand(&key_salt, &d2d_client, b"ENC:2", 32)?;
+        let client_hmac_key = hkdf_extract_expand(&key_salt, &d2d_client, b"SIG:1", 32)?;
+        let server_key = hkdf_extract_expand(&key_salt, &d2d_server, b"ENC:2", 32)?;
+        let server_hmac_key = hkdf_extract_expand(&key_salt, &d2d_server, b"SIG:1", 32)?;
+
+        let (encrypt_key, send_hmac_key, decrypt_key, recv_hmac_key) = match role {
+            SecureChannelRole::Client => (client_key, client_hmac_key, server_key, server_hmac_key),
+            SecureChannelRole::Server => (server_key, server_hmac_key, client_key, client_hmac_key),
+        };
+
+        Ok(Self {
+            role,
+            auth_string,
+            encrypt_key,
+            send_hmac_key,
+            decrypt_key,
+            recv_hmac_key,
+            send_seq: 0,
+            recv_seq: 0,
+        })
+    }
+
+    /// Four digits derived from the handshake, to be compared by the users.
+    pub fn pin_code(&self) -> String {
+        to_four_digit_string(&self.auth_string)
+    }
+
+    /// Wrap the message into a serialized SecureMessage.
+    pub fn encrypt(&mut self, message: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        self.encrypt_with_iv(message, &gen_random(IV_LEN))
+    }
+
+    fn encrypt_with_iv(&mut self, message: &[u8], iv: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        self.send_seq += 1;
+        let d2d_msg = DeviceToDeviceMessage {
+            sequence_number: Some(self.send_seq),
+            message: Some(message.to_vec()),
+        };
+
+        let mut cipher = Cipher::new_256(self.encrypt_key[..AES_256_KEY_LEN].try_into()?);
+        cipher.set_auto_padding(true);
+        let encrypted = cipher.cbc_encrypt(iv, &d2d_msg.encode_to_vec());
+
+        let hb = HeaderAndBody {
+            body: encrypted,
+            header: Header {
+                encryption_scheme: EncScheme::Aes256Cbc.into(),
+                signature_scheme: SigScheme::HmacSha256.into(),
+                iv: Some(iv.to_vec()),
+                public_metadata: Some(
+                    GcmMetadata {
+                        r#type: Type::DeviceToDeviceMessage.into(),
+                        version: Some(1),
+                    }
+                    .encode_to_vec(),
+                ),
+                ..Default::default()
+            },
+        }
+        .encode_to_vec();
+
+        let mut hmac = HmacSha256::new_from_slice(&self.send_hmac_key)?;
+        hmac.update(&hb);
+
+        let smsg = SecureMessage {
+            header_and_body: hb,
+            signature: hmac.finalize().into_bytes().to_vec(),
+        };
+
+        Ok(smsg.encode_to_vec())
+    }
+
+    /// Verify and decrypt a serialized SecureMessage, returning the message
+    /// it contains.
+    ///
+    /// Fails with a SecureChannelError if the message was altered, or isn't
+    /// the one following the previously received one.
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let smsg = SecureMessage::decode(data)?;
+
+        let mut hmac = HmacSha256::new_from_slice(&self.recv_hmac_key)?;
+        hmac.update(&smsg.header_and_body);
+        hmac.verify_slice(&smsg.signature)
+            .map_err(|_| SecureChannelError::InvalidSignature)?;
+
+        let header_and_body = HeaderAndBody::decode(&*smsg.header_and_body)?;
+        let iv = header_and_body.header.iv();
+        if iv.len() != IV_LEN {
+            return Err(anyhow!("Invalid IV length: {}", iv.len()));
+        }
+        // Padded to whole blocks, which are as long as the IV
+        let body = &header_and_body.body;
+        if body.is_empty() || body.len() % IV_LEN != 0 {
+            return Err(anyhow!("Invalid body length: {}", body.len()));
+        }
+
+        let mut cipher = Cipher::new_256(self.decrypt_key[..AES_256_KEY_LEN].try_into()?);
+        cipher.set_auto_padding(true);
+        let decrypted = cipher.cbc_decrypt(iv, body);
+
+        let d2d_msg = DeviceToDeviceMessage::decode(&*decrypted)?;
+
+        // Not advanced on failure, nothing can be received anymore anyway
+        let expected = self.recv_seq + 1;
+        if d2d_msg.sequence_number() != expected {
+            return Err(SecureChannelError::UnexpectedSequence {
+                expected,
+                received: d2d_msg.sequence_number(),
+            }
+            .into());
+        }
+        self.recv_seq = expected;
+
+        Ok(d2d_msg.message.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference values computed with an independent HKDF/AES-CBC implementation
+    const AUTH_STRING: &str = "505e17bae4251b870aa707a3fee2eb10b0a4e9c646d036daf4d129f5c1bd9587";
+    const CLIENT_KEY: &str = "59025c73f855d0a177a478b7df00cead092c23b610f10473c27a1cb7a1099eca";
+    const CLIENT_HMAC_KEY: &str =
+        "e09671468a2dbe2c2623af9e1ed2ee0acd8e0fa91eb23deb8c5c65655fb732c7";
+    const SERVER_KEY: &str = "483731f165752ddaa034f6d3b7f17361ea567a8283edfb78e67b0950b8d44762";
+    const SERVER_HMAC_KEY: &str =
+        "7224a8354922930f0dd1ae7ee448885a5723a30e39656ef210721896dbb42f83";
+    // "hello" sent by the client, with sequence number 1 and IV 01..10
+    const CLIENT_HELLO: &str = "0a300a1c080110022a100102030405060708090a0b0c0d0e0f103204080d10011210\
+        67437e96c24c6a1e747406268c4dcd1a1220609f3fdeda4a01a8684713b93810e5020fea2129baf4103c62e3339868e13d7b";
+
+    fn channel(role: SecureChannelRole) -> SecureChannel {
+        let derived_secret: Vec<u8> = (0..32).collect();
+        SecureChannel::new(role, &derived_secret, b"client init", b"server init").unwrap()
+    }
+
+    #[test]
+    fn test_key_schedule() {
+        let client = channel(SecureChannelRole::Client);
+        assert_eq!(hex::encode(&client.auth_string), AUTH_STRING);
+        assert_eq!(client.pin_code(), "6447");
+        assert_eq!(hex::encode(&client.encrypt_key), CLIENT_KEY);
+        assert_eq!(hex::encode(&client.send_hmac_key), CLIENT_HMAC_KEY);
+        assert_eq!(hex::encode(&client.decrypt_key), SERVER_KEY);
+        assert_eq!(hex::encode(&client.recv_hmac_key), SERVER_HMAC_KEY);
+
+        let server = channel(SecureChannelRole::Server);
+        assert_eq!(server.pin_code(), "6447");
+        assert_eq!(hex::encode(&server.encrypt_key), SERVER_KEY);
+        assert_eq!(hex::encode(&server.send_hmac_key), SERVER_HMAC_KEY);
+        assert_eq!(hex::encode(&server.decrypt_key), CLIENT_KEY);
+        assert_eq!(hex::encode(&server.recv_hmac_key), CLIENT_HMAC_KEY);
+    }
+
+    #[test]
+    fn test_encrypt_known_answer() {
+        let mut client = channel(SecureChannelRole::Client);
+        let iv: Vec<u8> = (1..=16).collect();
+        let smsg = client.encrypt_with_iv(b"hello", &iv).unwrap();
+        assert_eq!(hex::encode(smsg), CLIENT_HELLO);
+    }
+
+    #[test]
+    fn test_decrypt_known_answer() {
+        let mut server = channel(SecureChannelRole::Server);
+        let smsg = hex::decode(CLIENT_HELLO).unwrap();
+        assert_eq!(server.decrypt(&smsg).unwrap(), b"hello");
+
+        // Replaying it is rejected
+        let err = server.decrypt(&smsg).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SecureChannelError>(),
+            Some(&SecureChannelError::UnexpectedSequence {
+                expected: 2,
+                received: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_and_tampering() {
+        let mut client = channel(SecureChannelRole::Client);
+        let mut server = channel(SecureChannelRole::Server);
+
+        for i in 0..3u8 {
+            let smsg = server.encrypt(&[i; 100]).unwrap();
+            assert_eq!(client.decrypt(&smsg).unwrap(), vec![i; 100]);
+        }
+
+        // A client can't decrypt its own messages
+        let smsg = client.encrypt(b"ping").unwrap();
+        assert!(channel(SecureChannelRole::Client).decrypt(&smsg).is_err());
+
+        let mut tampered = smsg.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let err = server.decrypt(&tampered).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SecureChannelError>(),
+            Some(&SecureChannelError::InvalidSignature)
+        );
+        assert_eq!(server.decrypt(&smsg).unwrap(), b"ping");
+    }
+
+    #[test]
+    fn test_reordered_messages() {
+        let mut client = channel(SecureChannelRole::Client);
+        let mut server = channel(SecureChannelRole::Server);
+
+        let first = client.encrypt(b"first").unwrap();
+        let second = client.encrypt(b"second").unwrap();
+        let err = server.decrypt(&second).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SecureChannelError>(),
+            Some(&SecureChannelError::UnexpectedSequence {
+                expected: 1,
+                received: 2
+            })
+        );
+        assert_eq!(server.decrypt(&first).unwrap(), b"first");
+    }
+}
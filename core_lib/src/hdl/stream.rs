@@ -0,0 +1,51 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, DuplexStream, ReadBuf};
+
+// Size of the in-memory pipe between the connection and the consumer,
+// the connection will wait for the consumer to read once it's full.
+pub(crate) const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
+// How long the connection waits for the consumer to make room, it can't
+// answer anything else meanwhile (keep-alives included).
+pub(crate) const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A PayloadType::Stream received from a remote device.
+///
+/// The data can be read as it arrives, the reader reaches EOF once
+/// the remote device sent the last chunk of the payload.
+#[derive(Debug)]
+pub struct InboundStream {
+    // Same id as the one used in the ChannelMessage of the transfer
+    pub id: String,
+    pub payload_id: i64,
+    pub name: Option<String>,
+    reader: DuplexStream,
+}
+
+impl InboundStream {
+    pub(crate) fn new(
+        id: String,
+        payload_id: i64,
+        name: Option<String>,
+        reader: DuplexStream,
+    ) -> Self {
+        Self {
+            id,
+            payload_id,
+            name,
+            reader,
+        }
+    }
+}
+
+impl AsyncRead for InboundStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
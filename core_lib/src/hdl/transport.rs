@@ -0,0 +1,40 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+
+/// The carrier the Nearby protocol is spoken over, a TcpStream
+/// in practice but anything ordered and reliable will do.
+///
+/// Only transports with a local address can be upgraded to a
+/// WIFI_LAN socket, the others stay on the connection they started on.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Debug {
+    /// Address to offer the bandwidth upgrade on, if any.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Wrap the socket we upgraded to, must be Some if local_addr is.
+    fn from_upgraded(_socket: TcpStream) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+
+    fn from_upgraded(socket: TcpStream) -> Option<Self> {
+        Some(socket)
+    }
+}
+
+impl Transport for DuplexStream {}
+
+#[cfg(unix)]
+impl Transport for tokio::net::UnixStream {}
@@ -3,13 +3,13 @@ extern crate log;
 
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use channel::ChannelMessage;
 #[cfg(all(feature = "experimental", target_os = "linux"))]
 use hdl::BleAdvertiser;
 use hdl::MDnsDiscovery;
-use once_cell::sync::Lazy;
 use rand::distr::Alphanumeric;
 use rand::Rng;
 use tokio::net::TcpListener;
@@ -21,16 +21,31 @@ use tokio_util::task::TaskTracker;
 use crate::hdl::BleListener;
 use crate::hdl::MDnsServer;
 use crate::manager::TcpServer;
+use crate::queue::SendQueue;
 
 pub mod channel;
 mod errors;
 mod hdl;
 mod manager;
+mod queue;
 mod utils;
 
-pub use hdl::{EndpointInfo, OutboundPayload, State, Visibility};
-pub use manager::SendInfo;
-pub use utils::DeviceType;
+pub use hdl::{
+    EndpointInfo, InboundRequest, InboundStream, OutboundPayload, OutboundRequest, ProtocolError,
+    ResumeStore, SecureChannel, SecureChannelError, SecureChannelRole, State, Transport,
+    Visibility, WifiSecurityType,
+};
+pub use manager::{SendGroupInfo, SendInfo, SendTarget};
+pub use queue::RetryPolicy;
+pub use utils::{DeviceType, RemoteDeviceInfo};
+
+// What the fuzz targets (in fuzz/) feed with untrusted data, not a stable API
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    pub use crate::hdl::{
+        parse_connection_request, parse_ukey2_client_finish, parse_ukey2_client_init,
+    };
+}
 
 pub mod sharing_nearby {
     include!(concat!(env!("OUT_DIR"), "/sharing.nearby.rs"));
@@ -48,7 +63,7 @@ pub mod location_nearby_connections {
     include!(concat!(env!("OUT_DIR"), "/location.nearby.connections.rs"));
 }
 
-static CUSTOM_DOWNLOAD: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+const DEFAULT_MAX_OUTBOUND: usize = 4;
 
 #[derive(Debug)]
 pub struct RQS {
@@ -66,6 +81,31 @@ pub struct RQS {
     ble_sender: broadcast::Sender<()>,
 
     port_number: Option<u32>,
+    // Used instead of binding port_number, if set
+    listener: Option<TcpListener>,
+
+    // Per instance, so that several ones can live in the same process
+    download_path: Arc<RwLock<Option<PathBuf>>>,
+    consent_timeout: Arc<RwLock<Option<Duration>>>,
+    outbound_consent_timeout: Arc<RwLock<Option<Duration>>>,
+
+    // How many outbound transfers can run at the same time
+    max_outbound: usize,
+
+    // How the sends which couldn't reach the receiver are retried,
+    // and where they are kept to be resumed after a restart
+    retry_policy: RetryPolicy,
+    send_queue_path: Option<PathBuf>,
+    send_queue: Option<Arc<SendQueue>>,
+
+    // Set once running, removes the partial files on stop
+    resume_store: Option<Arc<ResumeStore>>,
+
+    // Only set if the consumer subscribed to the inbound streams
+    stream_sender: Option<mpsc::Sender<InboundStream>>,
+
+    // Set once running, for sends to several devices at once
+    group_sender: Option<mpsc::Sender<SendGroupInfo>>,
 
     pub message_sender: broadcast::Sender<ChannelMessage>,
 }
@@ -82,9 +122,6 @@ impl RQS {
         port_number: Option<u32>,
         download_path: Option<PathBuf>,
     ) -> Self {
-        let mut guard = CUSTOM_DOWNLOAD.write().unwrap();
-        *guard = download_path;
-
         let (message_sender, _) = broadcast::channel(50);
         let (ble_sender, _) = broadcast::channel(5);
 
@@ -100,10 +137,56 @@ impl RQS {
             visibility_receiver,
             ble_sender,
             port_number,
+            listener: None,
+            download_path: Arc::new(RwLock::new(download_path)),
+            consent_timeout: Arc::new(RwLock::new(None)),
+            outbound_consent_timeout: Arc::new(RwLock::new(None)),
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+            retry_policy: RetryPolicy::default(),
+            send_queue_path: None,
+            send_queue: None,
+            resume_store: None,
+            stream_sender: None,
+            group_sender: None,
             message_sender,
         }
     }
 
+    // Must be called before run(). The connections are accepted on this
+    // listener instead of port_number, for the next run() only.
+    pub fn set_listener(&mut self, listener: TcpListener) {
+        self.listener = Some(listener);
+    }
+
+    // Must be called before run(). Streams received while nobody is
+    // subscribed, or while the receiver is full, are written into the
+    // download directory instead.
+    pub fn subscribe_streams(&mut self) -> mpsc::Receiver<InboundStream> {
+        let (stream_sender, stream_receiver) = mpsc::channel(10);
+        self.stream_sender = Some(stream_sender);
+
+        stream_receiver
+    }
+
+    // Must be called before run(). Transfers sent while the limit
+    // is reached wait for another one to be done.
+    pub fn set_max_outbound_transfers(&mut self, max: usize) {
+        self.max_outbound = max;
+    }
+
+    // Must be called before run()
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    // Must be called before run(). The sends not done yet are kept in
+    // this file, and resumed by the next run(). The files being received
+    // are listed next to it, for the next run() to remove them if this
+    // one wasn't stopped.
+    pub fn set_send_queue_path(&mut self, p: Option<PathBuf>) {
+        self.send_queue_path = p;
+    }
+
     pub async fn run(
         &mut self,
     ) -> Result<(mpsc::Sender<SendInfo>, broadcast::Receiver<()>), anyhow::Error> {
@@ -117,23 +200,53 @@ impl RQS {
             .take(4)
             .map(u8::from)
             .collect();
-        let tcp_listener =
-            TcpListener::bind(format!("0.0.0.0:{}", self.port_number.unwrap_or(0))).await?;
+        let tcp_listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => TcpListener::bind(format!("0.0.0.0:{}", self.port_number.unwrap_or(0))).await?,
+        };
         let binded_addr = tcp_listener.local_addr()?;
         info!("TcpListener on: {}", binded_addr);
 
         // MPSC for the TcpServer
         let send_channel = mpsc::channel(10);
+        let group_channel = mpsc::channel(10);
+        self.group_sender = Some(group_channel.0);
+        let send_queue = Arc::new(SendQueue::new(
+            self.send_queue_path.clone(),
+            self.retry_policy.clone(),
+        ));
+        self.send_queue = Some(send_queue.clone());
+        // Leftovers of a previous run which wasn't stopped, removed
+        // before anything new is received.
+        let resume_store = Arc::new(ResumeStore::new(
+            self.send_queue_path
+                .as_ref()
+                .map(|p| p.with_extension("parts.json")),
+        ));
+        let store = resume_store.clone();
+        tokio::task::spawn_blocking(move || store.remove_leftovers()).await?;
+        self.resume_store = Some(resume_store.clone());
         // Start TcpServer in own "task"
         let mut server = TcpServer::new(
             endpoint_id[..4].try_into()?,
             tcp_listener,
             self.message_sender.clone(),
             send_channel.1,
+            group_channel.1,
+            self.stream_sender.clone(),
+            self.download_path.clone(),
+            self.consent_timeout.clone(),
+            self.outbound_consent_timeout.clone(),
+            self.max_outbound,
+            send_queue,
+            resume_store.clone(),
         )?;
         let ctk = ctoken.clone();
         tracker.spawn(async move { server.run(ctk).await });
 
+        let ctk = ctoken.clone();
+        tracker.spawn(async move { resume_store.expire(ctk).await });
+
         #[cfg(feature = "experimental")]
         {
             // Don't threat BleListener error as fatal, it's a nice to have.
@@ -159,6 +272,20 @@ impl RQS {
         Ok((send_channel.0, self.ble_sender.subscribe()))
     }
 
+    // Send the same payload to all the targets, in parallel (within the
+    // limit of set_max_outbound_transfers).
+    pub async fn send_to_group(&self, group: SendGroupInfo) -> Result<(), anyhow::Error> {
+        let group_sender = self
+            .group_sender
+            .as_ref()
+            .ok_or_else(|| anyhow!("The service wasn't first started"))?;
+
+        group_sender
+            .send(group)
+            .await
+            .map_err(|e| anyhow!("Couldn't send to the group: {e}"))
+    }
+
     pub fn discovery(
         &mut self,
         sender: broadcast::Sender<EndpointInfo>,
@@ -189,6 +316,13 @@ impl RQS {
             });
         }
 
+        // To find the devices again if their address changes before a retry
+        if let Some(send_queue) = self.send_queue.clone() {
+            let receiver = sender.subscribe();
+            let ctk = ctk.clone();
+            tracker.spawn(async move { send_queue.watch_endpoints(receiver, ctk).await });
+        }
+
         let discovery = MDnsDiscovery::new(sender)?;
         tracker.spawn(async move { discovery.run(ctk.clone()).await });
 
@@ -222,12 +356,34 @@ impl RQS {
 
         self.ctoken = None;
         self.tracker = None;
+        self.group_sender = None;
+        self.send_queue = None;
+
+        if let Some(resume_store) = self.resume_store.take() {
+            resume_store.clear();
+        }
     }
 
     // Setting None here will resume the default settings
     pub fn set_download_path(&self, p: Option<PathBuf>) {
         debug!("Setting the download path to {:?}", p);
-        let mut guard = CUSTOM_DOWNLOAD.write().unwrap();
+        let mut guard = self.download_path.write().unwrap();
         *guard = p;
     }
+
+    // How long an inbound transfer waits for the user to accept it,
+    // setting None here will resume the default timeout
+    pub fn set_consent_timeout(&self, t: Option<Duration>) {
+        debug!("Setting the consent timeout to {:?}", t);
+        let mut guard = self.consent_timeout.write().unwrap();
+        *guard = t;
+    }
+
+    // How long an outbound transfer waits for the remote user to accept it,
+    // setting None here will resume the default timeout
+    pub fn set_outbound_consent_timeout(&self, t: Option<Duration>) {
+        debug!("Setting the outbound consent timeout to {:?}", t);
+        let mut guard = self.outbound_consent_timeout.write().unwrap();
+        *guard = t;
+    }
 }
@@ -1,18 +1,30 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast::Sender;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::Semaphore;
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use ts_rs::TS;
 
 use crate::channel::{ChannelDirection, ChannelMessage};
 use crate::errors::AppError;
-use crate::hdl::{InboundRequest, OutboundPayload, OutboundRequest, State};
+use crate::hdl::{
+    prepare_files, InboundRequest, InboundStream, OutboundPayload, OutboundRequest, PreparedFile,
+    ResumeStore, State,
+};
+use crate::queue::{QueuedSend, SendQueue};
 use crate::utils::RemoteDeviceInfo;
 
 const INNER_NAME: &str = "TcpServer";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Deserialize, Serialize, TS)]
+#[derive(Debug, Clone, Deserialize, Serialize, TS)]
 #[ts(export)]
 pub struct SendInfo {
     pub id: String,
@@ -21,31 +33,92 @@ pub struct SendInfo {
     pub ob: OutboundPayload,
 }
 
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct SendTarget {
+    pub id: String,
+    pub name: String,
+    pub addr: String,
+}
+
+/// Same payload, sent to every target in parallel. Each transfer is
+/// reported under its target's id, with group_id set.
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct SendGroupInfo {
+    pub group_id: String,
+    pub targets: Vec<SendTarget>,
+    pub ob: OutboundPayload,
+}
+
+// What the transfers of a fan-out send share
+#[derive(Clone)]
+struct SendGroup {
+    id: String,
+    files: Option<Arc<Vec<PreparedFile>>>,
+}
+
 pub struct TcpServer {
     endpoint_id: [u8; 4],
     tcp_listener: TcpListener,
     sender: Sender<ChannelMessage>,
     connect_receiver: Receiver<SendInfo>,
+    group_receiver: Receiver<SendGroupInfo>,
+    stream_sender: Option<mpsc::Sender<InboundStream>>,
+    download_path: Arc<RwLock<Option<PathBuf>>>,
+    consent_timeout: Arc<RwLock<Option<Duration>>>,
+    outbound_consent_timeout: Arc<RwLock<Option<Duration>>>,
+    // Outbound transfers run on their own, at most max_outbound at once
+    outbound_tracker: TaskTracker,
+    outbound_slots: Arc<Semaphore>,
+    // Sends not done yet, retried when the receiver can't be reached
+    send_queue: Arc<SendQueue>,
+    resume: Arc<ResumeStore>,
 }
 
 impl TcpServer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         endpoint_id: [u8; 4],
         tcp_listener: TcpListener,
         sender: Sender<ChannelMessage>,
         connect_receiver: Receiver<SendInfo>,
+        group_receiver: Receiver<SendGroupInfo>,
+        stream_sender: Option<mpsc::Sender<InboundStream>>,
+        download_path: Arc<RwLock<Option<PathBuf>>>,
+        consent_timeout: Arc<RwLock<Option<Duration>>>,
+        outbound_consent_timeout: Arc<RwLock<Option<Duration>>>,
+        max_outbound: usize,
+        send_queue: Arc<SendQueue>,
+        resume: Arc<ResumeStore>,
     ) -> Result<Self, anyhow::Error> {
         Ok(Self {
             endpoint_id,
             tcp_listener,
             sender,
             connect_receiver,
+            group_receiver,
+            stream_sender,
+            download_path,
+            consent_timeout,
+            outbound_consent_timeout,
+            outbound_tracker: TaskTracker::new(),
+            outbound_slots: Arc::new(Semaphore::new(max_outbound.max(1))),
+            send_queue,
+            resume,
         })
     }
 
     pub async fn run(&mut self, ctk: CancellationToken) -> Result<(), anyhow::Error> {
         info!("{INNER_NAME}: service starting");
 
+        // Whatever wasn't sent before the last stop
+        for qs in self.send_queue.take_persisted() {
+            info!("{INNER_NAME}: resuming send to {}", qs.si.addr);
+            let group = qs.group_id.map(|id| SendGroup { id, files: None });
+            self.spawn_outbound(ctk.clone(), qs.si, group);
+        }
+
         loop {
             let cctk = ctk.clone();
 
@@ -56,9 +129,11 @@ impl TcpServer {
                 }
                 Some(i) = self.connect_receiver.recv() => {
                     info!("{INNER_NAME}: connect_receiver: got {:?}", i);
-                    if let Err(e) = self.connect(cctk, i).await {
-                        error!("{INNER_NAME}: error sending: {}", e.to_string());
-                    }
+                    self.spawn_outbound(cctk, i, None);
+                }
+                Some(g) = self.group_receiver.recv() => {
+                    info!("{INNER_NAME}: group_receiver: got {:?}", g);
+                    self.spawn_group(cctk, g);
                 }
                 r = self.tcp_listener.accept() => {
                     match r {
@@ -66,12 +141,25 @@ impl TcpServer {
                             trace!("{INNER_NAME}: new client: {remote_addr}");
                             let esender = self.sender.clone();
                             let csender = self.sender.clone();
+                            let stream_sender = self.stream_sender.clone();
+                            let download_path = self.download_path.clone();
+                            let consent_timeout = self.consent_timeout.clone();
+                            let resume = self.resume.clone();
 
                             tokio::spawn(async move {
-                                let mut ir = InboundRequest::new(socket, remote_addr.to_string(), csender);
+                                let mut ir = InboundRequest::new(socket, remote_addr.to_string(), csender, stream_sender, download_path, consent_timeout, resume);
 
                                 loop {
-                                    match ir.handle().await {
+                                    let r = tokio::select! {
+                                        _ = cctk.cancelled() => {
+                                            info!("{INNER_NAME}: tracker cancelled, dropping client");
+                                            ir.discard_files();
+                                            break;
+                                        }
+                                        r = ir.handle() => r,
+                                    };
+
+                                    match r {
                                         Ok(_) => {},
                                         Err(e) => match e.downcast_ref() {
                                             Some(AppError::NotAnError) => break,
@@ -105,25 +193,140 @@ impl TcpServer {
             }
         }
 
+        // Let the outbound transfers see the cancellation and stop
+        self.outbound_tracker.close();
+        self.outbound_tracker.wait().await;
+
         Ok(())
     }
 
-    /// To be called inside a separate task if we want to handle concurrency
-    pub async fn connect(&self, ctk: CancellationToken, si: SendInfo) -> Result<(), anyhow::Error> {
+    fn spawn_group(&self, ctk: CancellationToken, g: SendGroupInfo) {
+        // The files are looked at once, each transfer only opens them
+        let group = SendGroup {
+            id: g.group_id,
+            files: match &g.ob {
+                OutboundPayload::Files(files) => Some(Arc::new(prepare_files(files))),
+                _ => None,
+            },
+        };
+
+        for t in g.targets {
+            let si = SendInfo {
+                id: t.id,
+                name: t.name,
+                addr: t.addr,
+                ob: g.ob.clone(),
+            };
+            self.spawn_outbound(ctk.clone(), si, Some(group.clone()));
+        }
+    }
+
+    fn spawn_outbound(&self, ctk: CancellationToken, mut si: SendInfo, group: Option<SendGroup>) {
+        let endpoint_id = self.endpoint_id;
+        let sender = self.sender.clone();
+        let slots = self.outbound_slots.clone();
+        let queue = self.send_queue.clone();
+        let consent_timeout = self.outbound_consent_timeout.clone();
+        let key = queue.push(QueuedSend {
+            si: si.clone(),
+            group_id: group.as_ref().map(|g| g.id.clone()),
+        });
+
+        self.outbound_tracker.spawn(async move {
+            let mut attempt = 1;
+
+            loop {
+                let r = {
+                    // Queued until another outbound transfer is done
+                    let _permit = tokio::select! {
+                        _ = ctk.cancelled() => return,
+                        p = slots.acquire() => match p {
+                            Ok(p) => p,
+                            Err(_) => return,
+                        },
+                    };
+
+                    Self::connect(
+                        endpoint_id,
+                        sender.clone(),
+                        ctk.clone(),
+                        si.clone(),
+                        group.clone(),
+                        consent_timeout.clone(),
+                    )
+                    .await
+                };
+
+                let Err(e) = r else {
+                    break;
+                };
+                if attempt >= queue.policy.max_attempts {
+                    error!("{INNER_NAME}: giving up sending to {}: {e}", si.addr);
+                    let _ = sender.send(ChannelMessage {
+                        id: si.addr.clone(),
+                        direction: ChannelDirection::LibToFront,
+                        state: Some(State::Disconnected),
+                        group_id: group.as_ref().map(|g| g.id.clone()),
+                        ..Default::default()
+                    });
+                    break;
+                }
+
+                // Left in the queue if stopped meanwhile, to be sent after a restart
+                let backoff = queue.policy.backoff(attempt);
+                warn!(
+                    "{INNER_NAME}: couldn't send to {}: {e}, retrying in {backoff:?}",
+                    si.addr
+                );
+                tokio::select! {
+                    _ = ctk.cancelled() => return,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                attempt += 1;
+
+                if let Some(addr) = queue.resolve(&si) {
+                    info!("{INNER_NAME}: {} is now at {addr}", si.name);
+                    queue.set_addr(key, &addr);
+                    si.addr = addr;
+                }
+            }
+
+            queue.remove(key);
+        });
+    }
+
+    // An error means the receiver was never asked to accept the transfer
+    // (it couldn't be reached, or the handshake failed), so it can be retried.
+    async fn connect(
+        endpoint_id: [u8; 4],
+        sender: Sender<ChannelMessage>,
+        ctk: CancellationToken,
+        si: SendInfo,
+        group: Option<SendGroup>,
+        consent_timeout: Arc<RwLock<Option<Duration>>>,
+    ) -> Result<(), anyhow::Error> {
         debug!("{INNER_NAME}: Connecting to: {}", si.addr);
-        let socket = TcpStream::connect(si.addr.clone()).await?;
+        let group_id = group.as_ref().map(|g| g.id.clone());
+        // Not left to the OS, which may wait minutes while holding a slot
+        let socket = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(si.addr.clone()))
+            .await
+            .map_err(|_| anyhow!("connection timed out after {CONNECT_TIMEOUT:?}"))??;
 
         let mut or = OutboundRequest::new(
-            self.endpoint_id,
+            endpoint_id,
             socket,
             si.id,
-            self.sender.clone(),
+            sender.clone(),
             si.ob,
             RemoteDeviceInfo {
                 device_type: crate::DeviceType::Unknown,
                 name: si.name,
             },
+            consent_timeout,
         );
+        if let Some(g) = group {
+            or = or.with_group(g.id, g.files);
+        }
 
         // Send connection request
         or.send_connection_request().await?;
@@ -141,15 +344,16 @@ impl TcpServer {
                         match e.downcast_ref() {
                             Some(AppError::NotAnError) => break,
                             None => {
-                                if or.state.state == State::Initial {
-                                    break;
+                                if !asked_consent(&or.state.state) {
+                                    return Err(e);
                                 }
 
                                 if or.state.state != State::Finished && or.state.state != State::Cancelled {
-                                    let _ = self.sender.clone().send(ChannelMessage {
+                                    let _ = sender.send(ChannelMessage {
                                         id: si.addr,
                                         direction: ChannelDirection::LibToFront,
                                         state: Some(State::Disconnected),
+                                        group_id,
                                         ..Default::default()
                                     });
                                 }
@@ -165,3 +369,15 @@ impl TcpServer {
         Ok(())
     }
 }
+
+// Past the handshake, the remote user was asked (or is being asked) to accept
+fn asked_consent(state: &State) -> bool {
+    !matches!(
+        state,
+        State::Initial
+            | State::SentUkeyClientInit
+            | State::SentUkeyClientFinish
+            | State::SentPairedKeyEncryption
+            | State::SentPairedKeyResult
+    )
+}
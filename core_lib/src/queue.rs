@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::hdl::{EndpointInfo, OutboundPayload};
+use crate::manager::SendInfo;
+use crate::utils::write_atomic;
+
+const INNER_NAME: &str = "SendQueue";
+
+/// How a send is tried again when the receiver couldn't be reached
+/// (or the handshake failed), before it was asked to accept anything.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Including the first one, 1 means never retrying
+    pub max_attempts: u32,
+    // Doubled after each failed attempt, up to max_backoff
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the attempt following the `attempt`-th one.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct QueuedSend {
+    pub si: SendInfo,
+    pub group_id: Option<String>,
+}
+
+/// Sends not done yet, written to `path` (if any) each time it changes so
+/// that they are sent again after a restart.
+#[derive(Debug)]
+pub(crate) struct SendQueue {
+    path: Option<PathBuf>,
+    pub policy: RetryPolicy,
+    next_key: AtomicU64,
+    pending: Mutex<BTreeMap<u64, QueuedSend>>,
+    // Address of the devices seen by the discovery, by name
+    known: RwLock<HashMap<String, String>>,
+}
+
+impl SendQueue {
+    pub fn new(path: Option<PathBuf>, policy: RetryPolicy) -> Self {
+        Self {
+            path,
+            policy,
+            next_key: AtomicU64::new(0),
+            pending: Mutex::new(BTreeMap::new()),
+            known: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Sends left from the previous run, which are to be queued again.
+    pub fn take_persisted(&self) -> Vec<QueuedSend> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("{INNER_NAME}: ignoring unreadable {}: {e}", path.display());
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                error!("{INNER_NAME}: couldn't read {}: {e}", path.display());
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn push(&self, qs: QueuedSend) -> u64 {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let mut pending = self.pending.lock().unwrap();
+        pending.insert(key, qs);
+        self.persist(&pending);
+
+        key
+    }
+
+    pub fn set_addr(&self, key: u64, addr: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(qs) = pending.get_mut(&key) {
+            qs.si.addr = addr.to_owned();
+            self.persist(&pending);
+        }
+    }
+
+    pub fn remove(&self, key: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.remove(&key).is_some() {
+            self.persist(&pending);
+        }
+    }
+
+    fn persist(&self, pending: &BTreeMap<u64, QueuedSend>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        // Wi-Fi passwords are never written down, these sends are lost on restart
+        let sends: Vec<&QueuedSend> = pending
+            .values()
+            .filter(|qs| !matches!(qs.si.ob, OutboundPayload::WifiCredentials { .. }))
+            .collect();
+        if let Err(e) = write_atomic(path, &sends) {
+            error!("{INNER_NAME}: couldn't write {}: {e}", path.display());
+        }
+    }
+
+    /// Keep track of the discovered devices until `ctk` is cancelled.
+    pub async fn watch_endpoints(
+        &self,
+        mut receiver: broadcast::Receiver<EndpointInfo>,
+        ctk: CancellationToken,
+    ) {
+        loop {
+            let ei = tokio::select! {
+                _ = ctk.cancelled() => break,
+                r = receiver.recv() => match r {
+                    Ok(ei) => ei,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                },
+            };
+
+            let mut known = self.known.write().unwrap();
+            match (ei.name, ei.present) {
+                (Some(name), Some(true)) => {
+                    known.insert(ei.id, name);
+                }
+                _ => {
+                    known.remove(&ei.id);
+                }
+            }
+        }
+    }
+
+    /// New address of the device `si` was meant for, if it's not to be
+    /// found at its old one anymore. The device is looked up by name, and
+    /// only if there is no other one with the same name.
+    pub fn resolve(&self, si: &SendInfo) -> Option<String> {
+        let known = self.known.read().unwrap();
+        if known.contains_key(&si.addr) {
+            return None;
+        }
+
+        let mut same_name = known.iter().filter(|(_, name)| **name == si.name);
+        match (same_name.next(), same_name.next()) {
+            (Some((addr, _)), None) => Some(addr.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hdl::WifiSecurityType;
+
+    fn send_info(name: &str, addr: &str) -> SendInfo {
+        SendInfo {
+            id: String::from("id"),
+            name: name.to_owned(),
+            addr: addr.to_owned(),
+            ob: OutboundPayload::Text(String::from("text")),
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+
+        let delays: Vec<_> = (1..=5).map(|a| policy.backoff(a).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_persisted_queue() {
+        let path = std::env::temp_dir().join(format!("rqs_queue_{}.json", std::process::id()));
+        let queue = SendQueue::new(Some(path.clone()), RetryPolicy::default());
+
+        let first = queue.push(QueuedSend {
+            si: send_info("Phone", "10.0.0.1:1234"),
+            group_id: None,
+        });
+        queue.push(QueuedSend {
+            si: send_info("Tablet", "10.0.0.2:1234"),
+            group_id: Some(String::from("classroom")),
+        });
+        queue.remove(first);
+
+        let restored = SendQueue::new(Some(path.clone()), RetryPolicy::default()).take_persisted();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].si.name, "Tablet");
+        assert_eq!(restored[0].group_id.as_deref(), Some("classroom"));
+    }
+
+    #[test]
+    fn test_wifi_credentials_not_persisted() {
+        let path = std::env::temp_dir().join(format!("rqs_queue_wifi_{}.json", std::process::id()));
+        let queue = SendQueue::new(Some(path.clone()), RetryPolicy::default());
+
+        let mut wifi = send_info("Phone", "10.0.0.1:1234");
+        wifi.ob = OutboundPayload::WifiCredentials {
+            ssid: String::from("home"),
+            password: String::from("hunter22"),
+            security_type: WifiSecurityType::WpaPsk,
+            hidden: false,
+        };
+        queue.push(QueuedSend {
+            si: wifi,
+            group_id: None,
+        });
+        queue.push(QueuedSend {
+            si: send_info("Tablet", "10.0.0.2:1234"),
+            group_id: None,
+        });
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(&path).unwrap().permissions().mode()
+        };
+        let _ = std::fs::remove_file(&path);
+
+        assert!(!data.contains("hunter22"));
+        assert!(data.contains("Tablet"));
+        #[cfg(unix)]
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let queue = SendQueue::new(None, RetryPolicy::default());
+        let phone = send_info("Phone", "10.0.0.1:1234");
+        assert_eq!(queue.resolve(&phone), None);
+
+        let mut known = queue.known.write().unwrap();
+        known.insert(String::from("10.0.0.7:4321"), String::from("Phone"));
+        drop(known);
+        assert_eq!(queue.resolve(&phone), Some(String::from("10.0.0.7:4321")));
+
+        // Can't tell which one it was
+        let mut known = queue.known.write().unwrap();
+        known.insert(String::from("10.0.0.8:4321"), String::from("Phone"));
+        drop(known);
+        assert_eq!(queue.resolve(&phone), None);
+    }
+}
@@ -1,5 +1,8 @@
+use std::io::Write;
 use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
@@ -8,16 +11,20 @@ use bytes::Bytes;
 use get_if_addrs::get_if_addrs;
 use hkdf::Hkdf;
 use num_bigint::{BigUint, ToBigInt};
+use p256::ecdh::diffie_hellman;
 use p256::elliptic_curve::rand_core::OsRng;
-use p256::{PublicKey, SecretKey};
+use p256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use p256::{EncodedPoint, PublicKey, SecretKey};
+use prost::Message;
 use rand::{Rng, RngCore};
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use ts_rs::TS;
+use x25519_dalek::StaticSecret;
 
-use crate::CUSTOM_DOWNLOAD;
+use crate::securegcm::Ukey2HandshakeCipher;
+use crate::securemessage::{EcP256PublicKey, GenericPublicKey, PublicKeyType};
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize, TS)]
 #[ts(export)]
@@ -122,8 +129,8 @@ pub fn parse_mdns_endpoint_info(encoded_str: &str) -> Result<(DeviceType, String
     Ok((DeviceType::from_raw_value(device_type), device_name))
 }
 
-pub async fn stream_read_exact(
-    socket: &mut TcpStream,
+pub async fn stream_read_exact<R: AsyncRead + Unpin>(
+    socket: &mut R,
     buf: &mut [u8],
 ) -> Result<(), anyhow::Error> {
     match socket.read_exact(buf).await {
@@ -147,6 +154,105 @@ pub fn encode_point(unsigned: Bytes) -> Result<Vec<u8>, anyhow::Error> {
     Ok(big_int.to_signed_bytes_be())
 }
 
+/// StaticSecret doesn't implement Debug, which the handlers' state requires.
+pub struct X25519Secret(StaticSecret);
+
+impl std::fmt::Debug for X25519Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("X25519Secret(..)")
+    }
+}
+
+pub fn gen_x25519_keypair() -> (X25519Secret, x25519_dalek::PublicKey) {
+    let secret_key = StaticSecret::from(rand::rng().random::<[u8; 32]>());
+    let public_key = x25519_dalek::PublicKey::from(&secret_key);
+
+    (X25519Secret(secret_key), public_key)
+}
+
+/// Our public key, as sent in the UKEY2 ServerInit/ClientFinished.
+pub fn encode_p256_public_key(public_key: &PublicKey) -> Result<Vec<u8>, anyhow::Error> {
+    let encoded_point = public_key.to_encoded_point(false);
+    let x = encoded_point
+        .x()
+        .ok_or_else(|| anyhow!("Missing x coordinate"))?;
+    let y = encoded_point
+        .y()
+        .ok_or_else(|| anyhow!("Missing y coordinate"))?;
+
+    let pkey = GenericPublicKey {
+        r#type: PublicKeyType::EcP256.into(),
+        ec_p256_public_key: Some(EcP256PublicKey {
+            x: encode_point(Bytes::from(x.to_vec()))?,
+            y: encode_point(Bytes::from(y.to_vec()))?,
+        }),
+        ..Default::default()
+    };
+
+    Ok(pkey.encode_to_vec())
+}
+
+/// Sha256 of the Diffie-Hellman secret shared with the peer, whose public
+/// key is encoded as in its UKEY2 message for the negotiated cipher.
+pub fn ukey2_derived_secret(
+    cipher: Ukey2HandshakeCipher,
+    p256_key: Option<&SecretKey>,
+    x25519_key: Option<&X25519Secret>,
+    peer_key: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+    match cipher {
+        Ukey2HandshakeCipher::P256Sha512 => {
+            let priv_key = p256_key.ok_or_else(|| anyhow!("Missing P256 private key"))?;
+            let peer_p256_key = GenericPublicKey::decode(peer_key)?
+                .ec_p256_public_key
+                .ok_or_else(|| anyhow!("Missing required fields"))?;
+
+            let mut bytes = vec![0x04];
+            bytes.extend_from_slice(&to_coordinate(&peer_p256_key.x)?);
+            bytes.extend_from_slice(&to_coordinate(&peer_p256_key.y)?);
+
+            let encoded_point = EncodedPoint::from_bytes(bytes)?;
+            let peer_key = Option::<PublicKey>::from(PublicKey::from_encoded_point(&encoded_point))
+                .ok_or_else(|| anyhow!("Invalid P256 public key"))?;
+
+            let dhs = diffie_hellman(priv_key.to_nonzero_scalar(), peer_key.as_affine());
+            Ok(Sha256::digest(dhs.raw_secret_bytes()).to_vec())
+        }
+        Ukey2HandshakeCipher::Curve25519Sha512 => {
+            let priv_key = x25519_key.ok_or_else(|| anyhow!("Missing Curve25519 private key"))?;
+            let peer_key = <[u8; 32]>::try_from(peer_key)
+                .map_err(|_| anyhow!("Invalid Curve25519 public key length"))?;
+
+            let dhs = priv_key
+                .0
+                .diffie_hellman(&x25519_dalek::PublicKey::from(peer_key));
+            // A low order point would give an all-zero secret
+            if !dhs.was_contributory() {
+                return Err(anyhow!("Non contributory Curve25519 public key"));
+            }
+
+            Ok(Sha256::digest(dhs.as_bytes()).to_vec())
+        }
+        _ => Err(anyhow!("Unsupported handshake cipher: {:?}", cipher)),
+    }
+}
+
+// Coordinates are sent as signed big integers, so they may have
+// an extra leading zero, or fewer bytes than the field size.
+fn to_coordinate(raw: &[u8]) -> Result<[u8; 32], anyhow::Error> {
+    let raw = match raw.iter().position(|b| *b != 0) {
+        Some(start) => &raw[start..],
+        None => &[],
+    };
+    if raw.len() > 32 {
+        return Err(anyhow!("P256 coordinate too long"));
+    }
+
+    let mut coordinate = [0u8; 32];
+    coordinate[32 - raw.len()..].copy_from_slice(raw);
+    Ok(coordinate)
+}
+
 pub fn hkdf_extract_expand(
     salt: &[u8],
     input: &[u8],
@@ -182,8 +288,42 @@ pub fn gen_random(size: usize) -> Vec<u8> {
     data
 }
 
-pub fn get_download_dir() -> PathBuf {
-    let cdown = CUSTOM_DOWNLOAD.read();
+/// Free space (in bytes) of the filesystem where `dir` is, or will be created.
+pub fn available_space(dir: &Path) -> Result<u64, anyhow::Error> {
+    let existing = dir
+        .ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| anyhow!("No existing parent for {:?}", dir))?;
+
+    Ok(fs4::available_space(existing)?)
+}
+
+/// Write `value` as JSON to `path`, aside first so that a crash never
+/// leaves a truncated file. Only readable by the user, as what's kept
+/// there tells what was sent or received.
+pub fn write_atomic<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), anyhow::Error> {
+    let tmp = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    // A leftover one would keep its permissions
+    let _ = std::fs::remove_file(&tmp);
+    let mut file = options.open(&tmp)?;
+    file.write_all(&serde_json::to_vec(value)?)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+pub fn get_download_dir(custom: &RwLock<Option<PathBuf>>) -> PathBuf {
+    let cdown = custom.read();
     match cdown {
         Ok(mg) => {
             if mg.is_some() {
@@ -206,6 +346,108 @@ pub fn get_download_dir() -> PathBuf {
     Path::new("/").to_path_buf()
 }
 
+const DEFAULT_CONSENT_TIMEOUT: Duration = Duration::from_secs(60);
+// A bit longer than the receiver's own timeout, to get its TIMED_OUT answer
+const DEFAULT_OUTBOUND_CONSENT_TIMEOUT: Duration = Duration::from_secs(90);
+
+pub fn get_consent_timeout(custom: &RwLock<Option<Duration>>) -> Duration {
+    custom
+        .read()
+        .ok()
+        .and_then(|t| *t)
+        .unwrap_or(DEFAULT_CONSENT_TIMEOUT)
+}
+
+pub fn get_outbound_consent_timeout(custom: &RwLock<Option<Duration>>) -> Duration {
+    custom
+        .read()
+        .ok()
+        .and_then(|t| *t)
+        .unwrap_or(DEFAULT_OUTBOUND_CONSENT_TIMEOUT)
+}
+
+// Keep some room below the usual 255 bytes limit of filesystems for
+// the counter prefix added when the destination already exists.
+const MAX_FILENAME_BYTES: usize = 240;
+const MAX_EXTENSION_BYTES: usize = 16;
+const FALLBACK_FILENAME: &str = "file";
+
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turn a file name controlled by the remote device into a single, safe,
+/// path component: separators, control and bidi characters are removed,
+/// dot names and reserved names are rewritten and the length is capped.
+pub fn sanitize_filename(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !is_bidi_control(*c))
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            _ => c,
+        })
+        .collect();
+
+    // Leading dots would create hidden files (or "." / ".."), trailing
+    // dots and spaces are silently dropped by some filesystems.
+    let cleaned = cleaned
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' '])
+        .trim_start();
+    if cleaned.is_empty() {
+        return FALLBACK_FILENAME.to_owned();
+    }
+
+    let stem = cleaned.split('.').next().unwrap_or_default();
+    let mut cleaned = if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        format!("_{cleaned}")
+    } else {
+        cleaned.to_owned()
+    };
+
+    if cleaned.len() > MAX_FILENAME_BYTES {
+        cleaned = truncate_filename(&cleaned, MAX_FILENAME_BYTES);
+    }
+
+    cleaned
+}
+
+/// Sanitize each component of a relative folder sent by the remote
+/// device, components which can't be kept (empty, "." or "..") are
+/// dropped so that the result always stays below the base directory.
+pub fn sanitize_relative_path(path: &str) -> PathBuf {
+    path.split(['/', '\\'])
+        .filter(|c| !c.trim().is_empty() && c.trim() != "." && c.trim() != "..")
+        .map(sanitize_filename)
+        .collect()
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+// Truncate to max_bytes while keeping the extension (if reasonably
+// short) and without splitting a UTF-8 character.
+fn truncate_filename(name: &str, max_bytes: usize) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(idx) if idx > 0 && name.len() - idx <= MAX_EXTENSION_BYTES => name.split_at(idx),
+        _ => (name, ""),
+    };
+
+    let mut end = max_bytes.saturating_sub(ext.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &stem[..end], ext)
+}
+
 pub fn is_not_self_ip(ip_address: &Ipv4Addr) -> bool {
     if let Ok(if_addrs) = get_if_addrs() {
         for if_addr in if_addrs {
@@ -236,4 +478,128 @@ mod tests {
         assert_eq!(parse_info.1, device_name);
         assert_eq!(parse_info.0, device_type);
     }
+
+    #[test]
+    fn test_sanitize_filename_keeps_regular_names() {
+        assert_eq!(sanitize_filename("Cookbook.pdf"), "Cookbook.pdf");
+        assert_eq!(
+            sanitize_filename("photo 2024 (1).jpg"),
+            "photo 2024 (1).jpg"
+        );
+        assert_eq!(
+            sanitize_filename("日本語のファイル.txt"),
+            "日本語のファイル.txt"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_filename_hostile_inputs() {
+        let hostile = [
+            "../../etc/passwd",
+            "/etc/passwd",
+            "..\\..\\Windows\\system32",
+            "..",
+            ".",
+            "",
+            "   ",
+            "\0\n\r\t",
+            ".bashrc",
+            "a/../../b",
+            "C:\\evil.exe",
+        ];
+
+        for name in hostile {
+            let sanitized = sanitize_filename(name);
+            assert!(!sanitized.is_empty(), "{name:?}");
+            assert!(!sanitized.contains('/'), "{name:?} -> {sanitized:?}");
+            assert!(!sanitized.contains('\\'), "{name:?} -> {sanitized:?}");
+            assert!(!sanitized.starts_with('.'), "{name:?} -> {sanitized:?}");
+            assert!(
+                !sanitized.chars().any(|c| c.is_control()),
+                "{name:?} -> {sanitized:?}"
+            );
+            assert_eq!(Path::new(&sanitized).components().count(), 1);
+        }
+
+        assert_eq!(sanitize_filename("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(sanitize_filename(".."), FALLBACK_FILENAME);
+        assert_eq!(sanitize_filename("bad\u{0}name\n.txt"), "badname.txt");
+        assert_eq!(sanitize_filename("trailing. . "), "trailing");
+    }
+
+    #[test]
+    fn test_sanitize_filename_bidi_and_reserved() {
+        // "exe.txt" displayed, but "txt.exe" on disk
+        assert_eq!(
+            sanitize_filename("invoice\u{202E}txt.exe"),
+            "invoicetxt.exe"
+        );
+        assert_eq!(sanitize_filename("CON"), "_CON");
+        assert_eq!(sanitize_filename("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_filename("lpt1 .tar.gz"), "_lpt1 .tar.gz");
+        assert_eq!(sanitize_filename("console.txt"), "console.txt");
+    }
+
+    #[test]
+    fn test_sanitize_filename_length() {
+        let long = format!("{}.jpg", "a".repeat(1000));
+        let sanitized = sanitize_filename(&long);
+        assert_eq!(sanitized.len(), MAX_FILENAME_BYTES);
+        assert!(sanitized.ends_with(".jpg"));
+
+        // Multi-bytes chars must not be split
+        let long = "é".repeat(500);
+        let sanitized = sanitize_filename(&long);
+        assert!(sanitized.len() <= MAX_FILENAME_BYTES);
+        assert!(sanitized.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn test_sanitize_relative_path() {
+        assert_eq!(
+            sanitize_relative_path("Photos/2024"),
+            PathBuf::from("Photos/2024")
+        );
+        assert_eq!(sanitize_relative_path("../../../etc"), PathBuf::from("etc"));
+        assert_eq!(
+            sanitize_relative_path("/abs/./path\\..\\x"),
+            PathBuf::from("abs/path/x")
+        );
+        assert_eq!(sanitize_relative_path(""), PathBuf::new());
+    }
+
+    #[test]
+    fn test_ukey2_derived_secret() {
+        let (a_p256, a_p256_pub) = gen_ecdsa_keypair();
+        let (b_p256, b_p256_pub) = gen_ecdsa_keypair();
+        let (a_x25519, a_x25519_pub) = gen_x25519_keypair();
+        let (b_x25519, b_x25519_pub) = gen_x25519_keypair();
+
+        let cipher = Ukey2HandshakeCipher::P256Sha512;
+        let a = ukey2_derived_secret(
+            cipher,
+            Some(&a_p256),
+            None,
+            &encode_p256_public_key(&b_p256_pub).unwrap(),
+        )
+        .unwrap();
+        let b = ukey2_derived_secret(
+            cipher,
+            Some(&b_p256),
+            None,
+            &encode_p256_public_key(&a_p256_pub).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(a, b);
+
+        let cipher = Ukey2HandshakeCipher::Curve25519Sha512;
+        let a =
+            ukey2_derived_secret(cipher, None, Some(&a_x25519), b_x25519_pub.as_bytes()).unwrap();
+        let b =
+            ukey2_derived_secret(cipher, None, Some(&b_x25519), a_x25519_pub.as_bytes()).unwrap();
+        assert_eq!(a, b);
+
+        assert!(ukey2_derived_secret(cipher, None, Some(&a_x25519), &[0u8; 32]).is_err());
+        assert!(ukey2_derived_secret(cipher, None, Some(&a_x25519), &[1u8; 31]).is_err());
+    }
 }
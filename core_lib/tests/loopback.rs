@@ -0,0 +1,719 @@
+//! Transfers between two RQS instances over localhost, without any
+//! discovery (and so without needing a network), and between the bare
+//! handlers over an in-memory transport.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use prost::Message;
+use rqs_lib::channel::{ChannelAction, ChannelDirection, ChannelMessage, TransferType};
+use rqs_lib::location_nearby_connections::OfflineFrame;
+use rqs_lib::{
+    DeviceType, InboundRequest, OutboundPayload, OutboundRequest, RemoteDeviceInfo, ResumeStore,
+    RetryPolicy, SendGroupInfo, SendInfo, SendTarget, State, Visibility, RQS,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+// Asked by counting_proxy to the receiver, instead of the default 5s
+const PROXY_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Peer {
+    rqs: RQS,
+    send: mpsc::Sender<SendInfo>,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Peer {
+    async fn start(name: &str) -> Peer {
+        Self::start_with(name, any_listener().await, |_| {}).await
+    }
+
+    async fn start_with(
+        name: &str,
+        listener: TcpListener,
+        configure: impl FnOnce(&mut RQS),
+    ) -> Peer {
+        let dir = temp_dir(name);
+        let port = listener.local_addr().unwrap().port();
+        let mut rqs = RQS::new(Visibility::Invisible, None, Some(dir.clone()));
+        rqs.set_listener(listener);
+        configure(&mut rqs);
+        let (send, _) = rqs.run().await.expect("couldn't start RQS");
+
+        Peer {
+            rqs,
+            send,
+            port,
+            dir,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ChannelMessage> {
+        self.rqs.message_sender.subscribe()
+    }
+
+    async fn stop(mut self) {
+        self.rqs.stop().await;
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rqs_loopback_{}_{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+async fn any_listener() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").await.unwrap()
+}
+
+/// A port kept for the test, where connections are refused until
+/// it's listened on.
+fn reserve_port() -> (TcpSocket, u16) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(([127, 0, 0, 1], 0).into()).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    (socket, port)
+}
+
+// Deterministic bytes, not compressible and not all the same
+fn test_data(len: usize) -> Vec<u8> {
+    let mut x: u32 = 0x1234_5678;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+fn is_terminal(state: &State) -> bool {
+    matches!(
+        state,
+        State::Finished
+            | State::Rejected
+            | State::Cancelled
+            | State::Disconnected
+            | State::NotEnoughSpace
+            | State::TimedOut
+            | State::SecurityError
+    )
+}
+
+/// Collect the states the transfer of type `rtype` goes through, answering
+/// the consent request with `consent` (on the receiving side only).
+fn watch_states(
+    mut messages: broadcast::Receiver<ChannelMessage>,
+    front: broadcast::Sender<ChannelMessage>,
+    rtype: TransferType,
+    consent: Option<ChannelAction>,
+) -> JoinHandle<Vec<State>> {
+    tokio::spawn(async move {
+        let mut states: Vec<State> = Vec::new();
+
+        loop {
+            let msg = match messages.recv().await {
+                Ok(m) => m,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(e) => panic!("message channel closed: {e}"),
+            };
+            if msg.direction != ChannelDirection::LibToFront || msg.rtype != Some(rtype.clone()) {
+                continue;
+            }
+            let Some(state) = msg.state else {
+                continue;
+            };
+
+            // Progress updates repeat the same state
+            if states.last() != Some(&state) {
+                states.push(state.clone());
+            }
+
+            if state == State::WaitingForUserConsent {
+                if let Some(action) = consent.clone() {
+                    front
+                        .send(ChannelMessage {
+                            id: msg.id.clone(),
+                            direction: ChannelDirection::FrontToLib,
+                            action: Some(action),
+                            ..Default::default()
+                        })
+                        .unwrap();
+                }
+            }
+
+            if is_terminal(&state) {
+                return states;
+            }
+        }
+    })
+}
+
+async fn wait_states(handle: JoinHandle<Vec<State>>) -> Vec<State> {
+    tokio::time::timeout(TRANSFER_TIMEOUT, handle)
+        .await
+        .expect("transfer timed out")
+        .unwrap()
+}
+
+fn send_files(to: &Peer, files: &[PathBuf]) -> SendInfo {
+    SendInfo {
+        id: String::from("loopback"),
+        name: String::from("receiver"),
+        addr: format!("127.0.0.1:{}", to.port),
+        ob: OutboundPayload::Files(
+            files
+                .iter()
+                .map(|f| f.to_string_lossy().into_owned())
+                .collect(),
+        ),
+    }
+}
+
+/// Forward one connection to `to`, counting the frames going through. The
+/// WIFI_LAN medium is hidden, so that the transfer can't leave the proxy,
+/// and the receiver is asked for a keepalive every PROXY_KEEP_ALIVE_INTERVAL.
+async fn counting_proxy(to: u16) -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let frames = Arc::new(AtomicUsize::new(0));
+
+    let counter = frames.clone();
+    tokio::spawn(async move {
+        let (client, _) = listener.accept().await.unwrap();
+        let server = TcpStream::connect(("127.0.0.1", to)).await.unwrap();
+        let (client_read, client_write) = client.into_split();
+        let (server_read, server_write) = server.into_split();
+
+        tokio::spawn(forward_frames(
+            client_read,
+            server_write,
+            counter.clone(),
+            true,
+        ));
+        forward_frames(server_read, client_write, counter, false).await;
+    });
+
+    (port, frames)
+}
+
+async fn forward_frames(
+    mut from: OwnedReadHalf,
+    mut to: OwnedWriteHalf,
+    frames: Arc<AtomicUsize>,
+    mut connection_request: bool,
+) {
+    loop {
+        let mut length = [0u8; 4];
+        if from.read_exact(&mut length).await.is_err() {
+            return;
+        }
+        let mut frame = vec![0u8; u32::from_be_bytes(length) as usize];
+        if from.read_exact(&mut frame).await.is_err() {
+            return;
+        }
+
+        if connection_request {
+            connection_request = false;
+            let mut request = OfflineFrame::decode(frame.as_slice()).unwrap();
+            if let Some(cr) = request
+                .v1
+                .as_mut()
+                .and_then(|v1| v1.connection_request.as_mut())
+            {
+                cr.mediums.clear();
+                cr.keep_alive_interval_millis = Some(PROXY_KEEP_ALIVE_INTERVAL.as_millis() as i32);
+            }
+            frame = request.encode_to_vec();
+        }
+
+        frames.fetch_add(1, Ordering::SeqCst);
+        if to
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await
+            .is_err()
+            || to.write_all(&frame).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Id of the transfer once it waits for the user's consent.
+async fn wait_for_consent(mut messages: broadcast::Receiver<ChannelMessage>) -> String {
+    tokio::time::timeout(TRANSFER_TIMEOUT, async {
+        loop {
+            let msg = messages.recv().await.unwrap();
+            if msg.state == Some(State::WaitingForUserConsent) {
+                return msg.id;
+            }
+        }
+    })
+    .await
+    .expect("transfer never reached the consent")
+}
+
+fn write_file(dir: &Path, name: &str, data: &[u8]) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, data).unwrap();
+
+    path
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_send_files() {
+    let sender = Peer::start("send_files_sender").await;
+    let receiver = Peer::start("send_files_receiver").await;
+
+    // Big enough to be sent in several chunks
+    let big = test_data(3 * 1024 * 1024 + 17);
+    let small = b"Hello from the other side\n".to_vec();
+    let files = vec![
+        write_file(&sender.dir, "big.bin", &big),
+        write_file(&sender.dir, "small.txt", &small),
+    ];
+
+    let outbound = watch_states(
+        sender.subscribe(),
+        sender.rqs.message_sender.clone(),
+        TransferType::Outbound,
+        None,
+    );
+    let inbound = watch_states(
+        receiver.subscribe(),
+        receiver.rqs.message_sender.clone(),
+        TransferType::Inbound,
+        Some(ChannelAction::AcceptTransfer),
+    );
+    sender
+        .send
+        .send(send_files(&receiver, &files))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        wait_states(inbound).await,
+        vec![
+            State::WaitingForUserConsent,
+            State::ReceivingFiles,
+            State::Finished,
+        ]
+    );
+    assert_eq!(
+        wait_states(outbound).await,
+        vec![
+            State::SentUkeyClientInit,
+            State::SentIntroduction,
+            State::SendingFiles,
+            State::Finished,
+        ]
+    );
+
+    assert_eq!(std::fs::read(receiver.dir.join("big.bin")).unwrap(), big);
+    assert_eq!(
+        std::fs::read(receiver.dir.join("small.txt")).unwrap(),
+        small
+    );
+    assert_eq!(std::fs::read_dir(&receiver.dir).unwrap().count(), 2);
+
+    sender.stop().await;
+    receiver.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reject_files() {
+    let sender = Peer::start("reject_files_sender").await;
+    let receiver = Peer::start("reject_files_receiver").await;
+
+    let files = vec![write_file(&sender.dir, "unwanted.bin", &test_data(4096))];
+
+    let outbound = watch_states(
+        sender.subscribe(),
+        sender.rqs.message_sender.clone(),
+        TransferType::Outbound,
+        None,
+    );
+    let inbound = watch_states(
+        receiver.subscribe(),
+        receiver.rqs.message_sender.clone(),
+        TransferType::Inbound,
+        Some(ChannelAction::RejectTransfer),
+    );
+    sender
+        .send
+        .send(send_files(&receiver, &files))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        wait_states(inbound).await,
+        vec![State::WaitingForUserConsent, State::Rejected]
+    );
+    // A refusal is reported as a disconnection on the sending side
+    assert_eq!(
+        wait_states(outbound).await.last(),
+        Some(&State::Disconnected)
+    );
+
+    // Nothing was written, not even a partial file
+    assert_eq!(std::fs::read_dir(&receiver.dir).unwrap().count(), 0);
+
+    sender.stop().await;
+    receiver.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_receive_while_sending() {
+    let busy = Peer::start("receive_while_sending_busy").await;
+    let slow = Peer::start("receive_while_sending_slow").await;
+    let other = Peer::start("receive_while_sending_other").await;
+
+    // Nobody answers on the slow side, the transfer stays pending
+    let pending = watch_states(
+        slow.subscribe(),
+        slow.rqs.message_sender.clone(),
+        TransferType::Inbound,
+        None,
+    );
+    let big = write_file(&busy.dir, "big.bin", &test_data(1024 * 1024));
+    let consent = wait_for_consent(slow.subscribe());
+    busy.send.send(send_files(&slow, &[big])).await.unwrap();
+    consent.await;
+
+    let photo = test_data(64 * 1024);
+    let files = vec![write_file(&other.dir, "photo.jpg", &photo)];
+    let inbound = watch_states(
+        busy.subscribe(),
+        busy.rqs.message_sender.clone(),
+        TransferType::Inbound,
+        Some(ChannelAction::AcceptTransfer),
+    );
+    other.send.send(send_files(&busy, &files)).await.unwrap();
+
+    assert_eq!(wait_states(inbound).await.last(), Some(&State::Finished));
+    assert_eq!(std::fs::read(busy.dir.join("photo.jpg")).unwrap(), photo);
+    assert!(!pending.is_finished());
+
+    // Stopping doesn't wait for the pending transfer to be answered
+    tokio::time::timeout(TRANSFER_TIMEOUT, busy.stop())
+        .await
+        .expect("pending transfer blocked the shutdown");
+    slow.stop().await;
+    other.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_send_to_group() {
+    let teacher = Peer::start("send_to_group_teacher").await;
+    let students = [
+        Peer::start("send_to_group_student_a").await,
+        Peer::start("send_to_group_student_b").await,
+    ];
+
+    let handout = test_data(512 * 1024 + 3);
+    let files = [write_file(&teacher.dir, "handout.pdf", &handout)];
+
+    let inbounds: Vec<_> = students
+        .iter()
+        .map(|s| {
+            watch_states(
+                s.subscribe(),
+                s.rqs.message_sender.clone(),
+                TransferType::Inbound,
+                Some(ChannelAction::AcceptTransfer),
+            )
+        })
+        .collect();
+
+    // Last state of each transfer of the group, by target id
+    let mut messages = teacher.subscribe();
+    let outbound = tokio::spawn(async move {
+        let mut states: HashMap<String, State> = HashMap::new();
+        while states.len() < 2 || !states.values().all(is_terminal) {
+            let msg = messages.recv().await.unwrap();
+            if msg.group_id.as_deref() != Some("classroom") {
+                continue;
+            }
+            if let Some(state) = msg.state {
+                states.insert(msg.id, state);
+            }
+        }
+
+        states
+    });
+
+    teacher
+        .rqs
+        .send_to_group(SendGroupInfo {
+            group_id: String::from("classroom"),
+            targets: students
+                .iter()
+                .enumerate()
+                .map(|(i, s)| SendTarget {
+                    id: format!("student{i}"),
+                    name: format!("Student {i}"),
+                    addr: format!("127.0.0.1:{}", s.port),
+                })
+                .collect(),
+            ob: OutboundPayload::Files(
+                files
+                    .iter()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .collect(),
+            ),
+        })
+        .await
+        .unwrap();
+
+    for inbound in inbounds {
+        assert_eq!(wait_states(inbound).await.last(), Some(&State::Finished));
+    }
+    let states = tokio::time::timeout(TRANSFER_TIMEOUT, outbound)
+        .await
+        .expect("transfer timed out")
+        .unwrap();
+    assert_eq!(
+        states,
+        HashMap::from([
+            (String::from("student0"), State::Finished),
+            (String::from("student1"), State::Finished),
+        ])
+    );
+
+    for s in &students {
+        assert_eq!(std::fs::read(s.dir.join("handout.pdf")).unwrap(), handout);
+    }
+
+    teacher.stop().await;
+    for s in students {
+        s.stop().await;
+    }
+}
+
+fn quick_retries(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(500),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_retry_until_reachable() {
+    let sender = Peer::start_with("retry_sender", any_listener().await, |rqs| {
+        rqs.set_retry_policy(quick_retries(50))
+    })
+    .await;
+
+    // Nobody listens there yet, like a phone with its screen off
+    let (reserved, port) = reserve_port();
+    let data = test_data(16 * 1024);
+    let files = [write_file(&sender.dir, "late.bin", &data)];
+    let mut si = send_files(&sender, &files);
+    si.addr = format!("127.0.0.1:{port}");
+    sender.send.send(si).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let receiver = Peer::start_with("retry_receiver", reserved.listen(1024).unwrap(), |_| {}).await;
+    let inbound = watch_states(
+        receiver.subscribe(),
+        receiver.rqs.message_sender.clone(),
+        TransferType::Inbound,
+        Some(ChannelAction::AcceptTransfer),
+    );
+
+    assert_eq!(wait_states(inbound).await.last(), Some(&State::Finished));
+    assert_eq!(std::fs::read(receiver.dir.join("late.bin")).unwrap(), data);
+
+    sender.stop().await;
+    receiver.stop().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_queue_survives_restart() {
+    let queue_dir = temp_dir("restart_queue");
+    let queue_path = queue_dir.join("queue.json");
+    let data = test_data(16 * 1024);
+    let files = [write_file(&queue_dir, "queued.bin", &data)];
+    let (reserved, port) = reserve_port();
+
+    let sender = Peer::start_with("restart_sender", any_listener().await, |rqs| {
+        rqs.set_retry_policy(quick_retries(50));
+        rqs.set_send_queue_path(Some(queue_path.clone()));
+    })
+    .await;
+    let mut si = send_files(&sender, &files);
+    si.addr = format!("127.0.0.1:{port}");
+    sender.send.send(si).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    sender.stop().await;
+
+    let queued = std::fs::read_to_string(&queue_path).unwrap();
+    assert!(queued.contains(&format!("127.0.0.1:{port}")));
+
+    // The receiver is there now, the send resumes on its own
+    let receiver =
+        Peer::start_with("restart_receiver", reserved.listen(1024).unwrap(), |_| {}).await;
+    let inbound = watch_states(
+        receiver.subscribe(),
+        receiver.rqs.message_sender.clone(),
+        TransferType::Inbound,
+        Some(ChannelAction::AcceptTransfer),
+    );
+    let sender = Peer::start_with("restart_sender", any_listener().await, |rqs| {
+        rqs.set_send_queue_path(Some(queue_path.clone()));
+    })
+    .await;
+
+    assert_eq!(wait_states(inbound).await.last(), Some(&State::Finished));
+    assert_eq!(
+        std::fs::read(receiver.dir.join("queued.bin")).unwrap(),
+        data
+    );
+    tokio::time::timeout(TRANSFER_TIMEOUT, async {
+        while std::fs::read_to_string(&queue_path).unwrap() != "[]" {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("the send was never removed from the queue");
+
+    sender.stop().await;
+    receiver.stop().await;
+    let _ = std::fs::remove_dir_all(&queue_dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_keepalive_while_waiting() {
+    let sender = Peer::start("keepalive_sender").await;
+    let receiver = Peer::start("keepalive_receiver").await;
+    let (proxy_port, frames) = counting_proxy(receiver.port).await;
+
+    let data = test_data(1024);
+    let files = [write_file(&sender.dir, "waiting.bin", &data)];
+    let mut si = send_files(&receiver, &files);
+    si.addr = format!("127.0.0.1:{proxy_port}");
+    let inbound = watch_states(
+        receiver.subscribe(),
+        receiver.rqs.message_sender.clone(),
+        TransferType::Inbound,
+        None,
+    );
+    let consent = wait_for_consent(receiver.subscribe());
+    sender.send.send(si).await.unwrap();
+    let id = consent.await;
+
+    // Keepalives go on while nobody answers
+    let before = frames.load(Ordering::SeqCst);
+    tokio::time::timeout(TRANSFER_TIMEOUT, async {
+        while frames.load(Ordering::SeqCst) == before {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("no keepalive while waiting for the consent");
+
+    // Each one is acked once, then nothing until the next one. Answering
+    // the acks too would have them go back and forth without end.
+    let seen = frames.load(Ordering::SeqCst);
+    tokio::time::sleep(PROXY_KEEP_ALIVE_INTERVAL / 2).await;
+    let after = frames.load(Ordering::SeqCst) - seen;
+    assert!(after <= 4, "{after} frames right after a keepalive");
+
+    // The connection is still usable
+    receiver
+        .rqs
+        .message_sender
+        .send(ChannelMessage {
+            id,
+            direction: ChannelDirection::FrontToLib,
+            action: Some(ChannelAction::AcceptTransfer),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(wait_states(inbound).await.last(), Some(&State::Finished));
+    assert_eq!(
+        std::fs::read(receiver.dir.join("waiting.bin")).unwrap(),
+        data
+    );
+
+    sender.stop().await;
+    receiver.stop().await;
+}
+
+// The whole protocol (handshake, consent, payload, disconnection) with
+// both ends in the same process and no socket in between.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transfer_over_duplex() {
+    let send_dir = temp_dir("duplex_sender");
+    let recv_dir = temp_dir("duplex_receiver");
+    // Several chunks, and not a multiple of the chunk size
+    let data = test_data(1_500_000);
+    let file = write_file(&send_dir, "duplex.bin", &data);
+
+    let (inbound_socket, outbound_socket) = tokio::io::duplex(64 * 1024);
+    let (inbound_sender, _) = broadcast::channel(50);
+    let (outbound_sender, _) = broadcast::channel(50);
+    let inbound = watch_states(
+        inbound_sender.subscribe(),
+        inbound_sender.clone(),
+        TransferType::Inbound,
+        Some(ChannelAction::AcceptTransfer),
+    );
+    let outbound = watch_states(
+        outbound_sender.subscribe(),
+        outbound_sender.clone(),
+        TransferType::Outbound,
+        None,
+    );
+
+    let mut ir = InboundRequest::new(
+        inbound_socket,
+        String::from("inbound"),
+        inbound_sender,
+        None,
+        Arc::new(RwLock::new(Some(recv_dir.clone()))),
+        Arc::new(RwLock::new(None)),
+        Arc::new(ResumeStore::default()),
+    );
+    let mut or = OutboundRequest::new(
+        *b"DPLX",
+        outbound_socket,
+        String::from("outbound"),
+        outbound_sender,
+        OutboundPayload::Files(vec![file.to_string_lossy().into_owned()]),
+        RemoteDeviceInfo {
+            name: String::from("receiver"),
+            device_type: DeviceType::Laptop,
+        },
+        Arc::new(RwLock::new(None)),
+    );
+    tokio::spawn(async move { while ir.handle().await.is_ok() {} });
+    tokio::spawn(async move {
+        or.send_connection_request().await.unwrap();
+        or.send_ukey2_client_init().await.unwrap();
+        while or.handle().await.is_ok() {}
+    });
+
+    assert_eq!(wait_states(inbound).await.last(), Some(&State::Finished));
+    assert_eq!(wait_states(outbound).await.last(), Some(&State::Finished));
+    assert_eq!(std::fs::read(recv_dir.join("duplex.bin")).unwrap(), data);
+
+    let _ = std::fs::remove_dir_all(&send_dir);
+    let _ = std::fs::remove_dir_all(&recv_dir);
+}